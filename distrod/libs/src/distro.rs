@@ -11,7 +11,7 @@ use std::process::Command;
 
 use crate::container::{Container, ContainerLauncher, ContainerPath, HostPath};
 use crate::distrod_config::{self, DistrodConfig};
-use crate::envfile::{EnvFile, EnvShellScript};
+use crate::envfile::{EnvFile, EnvFileView, EnvModifier, EnvShellScript, DEFAULT_PATH};
 use crate::mount_info::get_mount_entries;
 pub use crate::multifork::Waiter;
 use crate::passwd::{get_real_credential, Credential};
@@ -184,12 +184,13 @@ impl DistroLauncher {
 
     fn mount_per_user_envs_script(&mut self) -> Result<()> {
         let mut env_shell_script = EnvShellScript::new();
-        for (key, value) in &self.per_user_envs {
-            env_shell_script.put_env(key.clone(), value.clone());
-        }
-        for (path, prepends) in &self.per_user_paths {
-            env_shell_script.put_path(path.clone(), *prepends);
-        }
+        put_envs_and_paths(
+            &mut env_shell_script,
+            &self.per_user_envs,
+            self.per_user_paths
+                .iter()
+                .map(|(path, prepends)| (path.as_str(), *prepends)),
+        )?;
 
         let real_user =
             get_real_credential().with_context(|| "Failed to get the real credentail.")?;
@@ -483,18 +484,40 @@ fn write_system_env_files(
     let env_file_path = &ContainerPath::new("/etc/environment")?.to_host_path(&rootfs_path);
     let mut env_file = EnvFile::open(&env_file_path)
         .with_context(|| format!("Failed to open '{:?}'.", &env_file_path))?;
-    for (name, value) in envs {
-        env_file.put_env(name, value);
-    }
-    for path in paths {
-        env_file.put_path(path);
-    }
+    if env_file.was_created() {
+        env_file
+            .seed_defaults(&[("PATH", DEFAULT_PATH), ("LANG", "C.UTF-8")])
+            .with_context(|| format!("Failed to seed the defaults to {:?}", env_file_path))?;
+    }
+    put_envs_and_paths(
+        &mut env_file,
+        &envs,
+        paths.iter().map(|path| (path.as_str(), true)),
+    )
+    .with_context(|| format!("Failed to put the variables to {:?}", env_file_path))?;
     env_file
         .write()
         .with_context(|| format!("Failed to write system env file on {:?}", env_file_path))?;
     Ok(())
 }
 
+fn put_envs_and_paths<'a, I>(
+    modifier: &mut dyn EnvModifier,
+    envs: &HashMap<String, String>,
+    paths: I,
+) -> Result<()>
+where
+    I: Iterator<Item = (&'a str, bool)>,
+{
+    for (key, value) in envs {
+        modifier.put_env(key, value)?;
+    }
+    for (path, prepends) in paths {
+        modifier.put_path(path, prepends)?;
+    }
+    Ok(())
+}
+
 pub struct Distro {
     rootfs: PathBuf,
     container: Container,
@@ -796,7 +819,7 @@ enum DistroName {
 }
 
 fn detect_distro(rootfs: &HostPath) -> Result<DistroName> {
-    let os_release = EnvFile::open(ContainerPath::new("/etc/os-release")?.to_host_path(rootfs))
+    let os_release = EnvFileView::open(ContainerPath::new("/etc/os-release")?.to_host_path(rootfs))
         .with_context(|| "Failed to parse /etc/os-release.");
     if let Err(ref e) = os_release {
         if e.downcast_ref::<std::io::Error>().map(|e| e.kind())
@@ -805,24 +828,13 @@ fn detect_distro(rootfs: &HostPath) -> Result<DistroName> {
             return Ok(DistroName::Undetected);
         }
     }
-    match os_release?.get_env("ID").map(strip_quotes) {
+    match os_release?.get_env("ID").as_deref() {
         Some("debian") => Ok(DistroName::Debian),
         Some("kali") => Ok(DistroName::Kali),
         _ => Ok(DistroName::Undetected),
     }
 }
 
-fn strip_quotes(s: &str) -> &str {
-    let mut result = s;
-    if s.starts_with('"') {
-        result = &result[1..result.len()];
-    }
-    if s.ends_with('"') {
-        result = &result[0..result.len() - 1];
-    }
-    result
-}
-
 fn initialize_debian_rootfs(rootfs: &HostPath, overwrites_potential_userfiles: bool) -> Result<()> {
     if overwrites_potential_userfiles {
         // Ubuntu doesn't need this.
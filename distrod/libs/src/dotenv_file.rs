@@ -0,0 +1,412 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::envfile::{validate_key, EnvFile};
+
+/// DotEnvFile understands the .env files of docker-compose and other dotenv tools.
+/// Values can be double-quoted with escapes, single-quoted literally, or bare, and lines it
+/// doesn't understand are kept as they are.
+#[derive(Debug, Clone)]
+pub struct DotEnvFile {
+    pub file_path: PathBuf,
+    envs: HashMap<String, usize>,
+    lines: Vec<DotEnvLine>,
+}
+
+#[derive(Debug, Clone)]
+enum DotEnvLine {
+    Env(DotEnvStatement),
+    Other(String),
+}
+
+#[derive(Debug, Clone)]
+struct DotEnvStatement {
+    // Indentation and the optional `export `
+    leading_characters: String,
+    key: String,
+    // `=` and the spaces around it
+    separator: String,
+    // The value as written in the file, including the quotes
+    raw_value: String,
+    // Spaces and the comment after the value, and the line ending
+    following_characters: String,
+}
+
+impl DotEnvFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DotEnvFile> {
+        let cont = match std::fs::read_to_string(path.as_ref()) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            cont => cont.with_context(|| format!("Failed to read {:?}", path.as_ref()))?,
+        };
+        Ok(DotEnvFile::parse(path.as_ref().to_owned(), &cont))
+    }
+
+    fn parse(file_path: PathBuf, cont: &str) -> DotEnvFile {
+        let mut envs = HashMap::new();
+        let mut lines = vec![];
+        for line in cont.split_inclusive('\n') {
+            match DotEnvStatement::parse(line) {
+                Some(statement) => {
+                    envs.insert(statement.key.clone(), lines.len());
+                    lines.push(DotEnvLine::Env(statement));
+                }
+                None => lines.push(DotEnvLine::Other(line.to_owned())),
+            }
+        }
+        DotEnvFile {
+            file_path,
+            envs,
+            lines,
+        }
+    }
+
+    /// Returns the value with the quotes removed and the escapes in double quotes decoded.
+    /// `${VAR}` is returned as it is, not interpolated.
+    pub fn get_env(&self, key: &str) -> Option<String> {
+        Some(self.get_statement(key)?.value())
+    }
+
+    /// Returns the defined keys in the order of their effective definitions.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys: Vec<_> = self.envs.iter().collect();
+        keys.sort_by_key(|(_, index)| **index);
+        keys.into_iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    pub fn put_env(&mut self, key: String, value: String) {
+        let raw_value = quote_dotenv_value(&value);
+        match self.envs.get(&key) {
+            Some(index) => match self.lines[*index] {
+                DotEnvLine::Env(ref mut statement) => statement.raw_value = raw_value,
+                _ => unreachable!(),
+            },
+            None => {
+                // Follow the line ending of the file, which may be CRLF.
+                let line_ending = match self.lines.last() {
+                    Some(DotEnvLine::Env(statement))
+                        if statement.following_characters.ends_with("\r\n") =>
+                    {
+                        "\r\n"
+                    }
+                    Some(DotEnvLine::Other(line)) if line.ends_with("\r\n") => "\r\n",
+                    _ => "\n",
+                };
+                self.terminate_last_line();
+                self.lines.push(DotEnvLine::Env(DotEnvStatement {
+                    leading_characters: String::new(),
+                    key: key.clone(),
+                    separator: "=".to_owned(),
+                    raw_value,
+                    following_characters: line_ending.to_owned(),
+                }));
+                self.envs.insert(key, self.lines.len() - 1);
+            }
+        }
+    }
+
+    /// Copy all the variables into a pam_env style file, re-quoting the values for it.
+    /// Nothing is copied if a value can't be represented there.
+    pub fn merge_into(&self, target: &mut EnvFile) -> Result<()> {
+        let envs: Vec<_> = self
+            .keys()
+            .into_iter()
+            .map(|key| (key, self.get_env(key).unwrap()))
+            .collect();
+        for (key, value) in &envs {
+            validate_key(key)?;
+            if value.contains('\n') {
+                bail!(
+                    "The value of {} in {:?} can't be put to {:?}: {:?}",
+                    key,
+                    &self.file_path,
+                    &target.file_path,
+                    value
+                );
+            }
+        }
+        for (key, value) in envs {
+            target.put_env(key.to_owned(), value)?;
+        }
+        Ok(())
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut cont = String::new();
+        for line in &self.lines {
+            match line {
+                DotEnvLine::Env(statement) => cont.push_str(&statement.serialize()),
+                DotEnvLine::Other(line) => cont.push_str(line),
+            }
+        }
+        cont
+    }
+
+    pub fn write(&mut self) -> Result<()> {
+        let mut file = BufWriter::new(
+            File::create(&self.file_path)
+                .with_context(|| format!("Failed to create {:?}.", &self.file_path))?,
+        );
+        file.write_all(self.serialize().as_bytes())?;
+        file.flush()
+            .with_context(|| format!("Failed to write {:?}.", &self.file_path))?;
+        Ok(())
+    }
+
+    fn get_statement(&self, key: &str) -> Option<&DotEnvStatement> {
+        match self.lines[*self.envs.get(key)?] {
+            DotEnvLine::Env(ref statement) => Some(statement),
+            _ => unreachable!(),
+        }
+    }
+
+    fn terminate_last_line(&mut self) {
+        match self.lines.last_mut() {
+            Some(DotEnvLine::Env(statement)) if !statement.following_characters.ends_with('\n') => {
+                statement.following_characters.push('\n')
+            }
+            Some(DotEnvLine::Other(line)) if !line.ends_with('\n') => line.push('\n'),
+            _ => {}
+        }
+    }
+}
+
+impl DotEnvStatement {
+    fn parse(line: &str) -> Option<DotEnvStatement> {
+        let rest = line.trim_start_matches(&[' ', '\t'][..]);
+        let mut leading_len = line.len() - rest.len();
+        if let Some(after_export) = rest.strip_prefix("export") {
+            let after_spaces = after_export.trim_start_matches(&[' ', '\t'][..]);
+            if after_spaces.len() < after_export.len() {
+                leading_len = line.len() - after_spaces.len();
+            }
+        }
+        let (leading_characters, rest) = line.split_at(leading_len);
+
+        let key_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        let (key, rest) = rest.split_at(key_len);
+        if key.is_empty() || key.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+
+        let value = rest.trim_start_matches(&[' ', '\t'][..]);
+        let value = value.strip_prefix('=')?;
+        let value = value.trim_start_matches(&[' ', '\t'][..]);
+        let (separator, rest) = rest.split_at(rest.len() - value.len());
+
+        let value_len = match rest.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => closing_quote_index(rest, quote)? + 1,
+            _ => {
+                // A bare value ends at a comment, which starts with `#` after a space.
+                let end = rest.trim_end_matches(&['\n', '\r'][..]).len();
+                let comment = rest[..end]
+                    .find(" #")
+                    .into_iter()
+                    .chain(rest[..end].find("\t#"))
+                    .min()
+                    .unwrap_or(end);
+                rest[..comment].trim_end().len()
+            }
+        };
+        let (raw_value, following_characters) = rest.split_at(value_len);
+        let trailer = following_characters.trim_start_matches(&[' ', '\t'][..]);
+        if !(trailer.is_empty()
+            || trailer.starts_with('#')
+            || trailer.starts_with(&['\r', '\n'][..]))
+        {
+            return None;
+        }
+
+        Some(DotEnvStatement {
+            leading_characters: leading_characters.to_owned(),
+            key: key.to_owned(),
+            separator: separator.to_owned(),
+            raw_value: raw_value.to_owned(),
+            following_characters: following_characters.to_owned(),
+        })
+    }
+
+    fn value(&self) -> String {
+        let raw = self.raw_value.as_str();
+        if raw.len() >= 2 && raw.starts_with('\'') {
+            return raw[1..raw.len() - 1].to_owned();
+        }
+        if raw.len() >= 2 && raw.starts_with('"') {
+            return unescape_double_quoted(&raw[1..raw.len() - 1]);
+        }
+        raw.to_owned()
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "{}{}{}{}{}",
+            self.leading_characters,
+            self.key,
+            self.separator,
+            self.raw_value,
+            self.following_characters
+        )
+    }
+}
+
+/// Returns the index of the quote closing the one at the beginning of `s`.
+fn closing_quote_index(s: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote == '"' {
+            escaped = true;
+        } else if c == quote {
+            return Some(i);
+        }
+    }
+    None
+}
+
+fn unescape_double_quoted(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') => result.push(c),
+            Some(c) => {
+                result.push('\\');
+                result.push(c);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Single-quote the value if possible since it's literal, or double-quote it with escapes.
+fn quote_dotenv_value(value: &str) -> String {
+    if !value.contains(&['\'', '\n', '\t', '\r'][..]) {
+        return format!("'{}'", value);
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '"' | '\\' | '$' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::*;
+
+    #[test]
+    fn test_get() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        let cont = "\
+            # comment\n\
+            export EXPORTED=exported\n\
+            BARE=bare value # comment\n\
+            HASH_IN_DOUBLE=\"foo # not a comment\" # comment\n\
+            HASH_IN_SINGLE='foo # not a comment'\n\
+            ESCAPED=\"line1\\nline2\\t\\\"quoted\\\" ${HOME}\"\n\
+            LITERAL='no\\nescape ${HOME}'\n\
+            EMPTY=\n\
+            EMPTY_QUOTED=\"\"\n\
+            SPACES = around\n\
+            NOT A STATEMENT\n\
+        ";
+        write!(&mut tmp, "{}", cont).unwrap();
+        let env = DotEnvFile::open(tmp.path()).unwrap();
+
+        assert_eq!(Some("exported".to_owned()), env.get_env("EXPORTED"));
+        assert_eq!(Some("bare value".to_owned()), env.get_env("BARE"));
+        assert_eq!(
+            Some("foo # not a comment".to_owned()),
+            env.get_env("HASH_IN_DOUBLE")
+        );
+        assert_eq!(
+            Some("foo # not a comment".to_owned()),
+            env.get_env("HASH_IN_SINGLE")
+        );
+        assert_eq!(
+            Some("line1\nline2\t\"quoted\" ${HOME}".to_owned()),
+            env.get_env("ESCAPED")
+        );
+        assert_eq!(
+            Some("no\\nescape ${HOME}".to_owned()),
+            env.get_env("LITERAL")
+        );
+        assert_eq!(Some("".to_owned()), env.get_env("EMPTY"));
+        assert_eq!(Some("".to_owned()), env.get_env("EMPTY_QUOTED"));
+        assert_eq!(Some("around".to_owned()), env.get_env("SPACES"));
+        assert_eq!(None, env.get_env("NOT"));
+        assert_eq!(cont, env.serialize());
+    }
+
+    #[test]
+    fn test_put_env_and_save() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        let cont = "# comment\r\nFOO=foo # comment\r\nexport BAR=\"bar\"\r\n";
+        write!(&mut tmp, "{}", cont).unwrap();
+        let mut env = DotEnvFile::open(tmp.path()).unwrap();
+        env.put_env("FOO".to_owned(), "it's a foo".to_owned());
+        env.put_env("BAR".to_owned(), "${NOT_INTERPOLATED}".to_owned());
+        env.put_env("NEW".to_owned(), "line1\nline2".to_owned());
+        env.write().unwrap();
+
+        let cont = std::fs::read_to_string(tmp.path()).unwrap();
+        assert_eq!(
+            "# comment\r\n\
+             FOO=\"it's a foo\" # comment\r\n\
+             export BAR='${NOT_INTERPOLATED}'\r\n\
+             NEW=\"line1\\nline2\"\r\n",
+            &cont
+        );
+        let env = DotEnvFile::open(tmp.path()).unwrap();
+        assert_eq!(Some("it's a foo".to_owned()), env.get_env("FOO"));
+        assert_eq!(Some("line1\nline2".to_owned()), env.get_env("NEW"));
+    }
+
+    #[test]
+    fn test_merge_into() {
+        let mut dot_env = DotEnvFile::parse(
+            PathBuf::from(".env"),
+            "FOO=\"foo bar\"\nEMPTY=\nQUOTE=\"it's\"\n",
+        );
+        let mut tmp = NamedTempFile::new().unwrap();
+        writeln!(&mut tmp, "FOO=old").unwrap();
+        let mut env_file = EnvFile::open(tmp.path()).unwrap();
+        dot_env.merge_into(&mut env_file).unwrap();
+        assert_eq!(Some("foo bar".to_owned()), env_file.get_env("FOO"));
+        assert_eq!(Some("".to_owned()), env_file.get_env("EMPTY"));
+        assert_eq!(Some("it's".to_owned()), env_file.get_env("QUOTE"));
+        assert_eq!(Some("'foo bar'"), env_file.get_env_raw("FOO"));
+
+        dot_env.put_env("MULTILINE".to_owned(), "line1\nline2".to_owned());
+        let mut env_file = EnvFile::open(tmp.path()).unwrap();
+        assert!(dot_env.merge_into(&mut env_file).is_err());
+        assert_eq!(Some("old".to_owned()), env_file.get_env("FOO"));
+    }
+}
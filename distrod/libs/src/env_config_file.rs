@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::envfile::{validate_key, EnvConfig, EnvFile, EnvShellScript};
+
+/// The schema of a TOML file that describes the environment, like
+///
+/// ```toml
+/// [env]
+/// JAVA_HOME = "/opt/jdk"
+/// JAVA_OPTS = "${env:JAVA_OPTS} -Xmx1g"
+///
+/// [path]
+/// prepend = ["/opt/jdk/bin"]
+/// append = ["/mnt/c/Windows/System32"]
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct EnvConfigToml {
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    path: PathTable,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct PathTable {
+    #[serde(default)]
+    prepend: Vec<String>,
+    #[serde(default)]
+    append: Vec<String>,
+}
+
+pub struct EnvConfigFile;
+
+impl EnvConfigFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<EnvConfig> {
+        let cont = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Failed to read {:?}", path.as_ref()))?;
+        EnvConfigFile::parse(path.as_ref(), &cont)
+    }
+
+    fn parse(path: &Path, cont: &str) -> Result<EnvConfig> {
+        // The error of toml tells the line, and unknown keys are rejected by deny_unknown_fields.
+        let config: EnvConfigToml =
+            toml::from_str(cont).map_err(|e| anyhow!("Failed to parse {:?}: {}", path, e))?;
+        for (key, value) in &config.env {
+            let line = find_line(cont, |line| is_assignment_of(line, key));
+            if validate_key(key).is_err() {
+                bail!("{}: invalid variable name {:?}", location(path, line), key);
+            }
+            if value.contains('\n') || value.contains('\\') || value.contains('\0') {
+                bail!(
+                    "{}: the value of {} has a newline, a backslash or NUL",
+                    location(path, line),
+                    key
+                );
+            }
+        }
+        for elem in config.path.prepend.iter().chain(config.path.append.iter()) {
+            let line = find_line(cont, |line| line.contains(&format!("{:?}", elem)));
+            if !elem.starts_with('/') || elem.contains(&[':', '"', '\'', '\\', '\n'][..]) {
+                bail!(
+                    "{}: {:?} in [path] must be an absolute path without colons, quotes or backslashes",
+                    location(path, line),
+                    elem
+                );
+            }
+        }
+        Ok(EnvConfig {
+            envs: config.env,
+            prepend_paths: config.path.prepend,
+            append_paths: config.path.append,
+        })
+    }
+}
+
+/// Apply `config` to `env_file`. `${env:NAME}` in the values is replaced with the value of
+/// NAME in `env_file` before any of the variables are put. `env_file` is left unchanged if
+/// any of them can't be put.
+pub fn apply_to_env_file(config: &EnvConfig, env_file: &mut EnvFile) -> Result<()> {
+    let mut expanded = config.clone();
+    for (key, value) in expanded.envs.iter_mut() {
+        *value = expand_env_references(value, |name| env_file.get_env(name))
+            .with_context(|| format!("Failed to expand the value of {}", key))?;
+    }
+    env_file.apply_config(&expanded)
+}
+
+/// Apply `config` to `script`. References to other variables are rejected, since the script
+/// doesn't know their values.
+pub fn apply_to_shell_script(config: &EnvConfig, script: &mut EnvShellScript) -> Result<()> {
+    for (key, value) in &config.envs {
+        if value.contains("${env:") {
+            bail!(
+                "The value of {} references another variable, which a shell script doesn't support.",
+                key
+            );
+        }
+    }
+    script.merge(&EnvShellScript::from_config(config)?);
+    Ok(())
+}
+
+fn expand_env_references<F>(value: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${env:") {
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start + "${env:".len()..];
+        let end = match reference.find('}') {
+            Some(end) => end,
+            None => bail!("Unterminated reference in {:?}", value),
+        };
+        let name = &reference[..end];
+        match lookup(name) {
+            Some(referenced) => expanded.push_str(&referenced),
+            None => bail!("{} is referenced but not defined.", name),
+        }
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn is_assignment_of(line: &str, key: &str) -> bool {
+    let line = line.trim_start();
+    let rest = match line.strip_prefix(key) {
+        Some(rest) => rest,
+        // A key that isn't a bare key of TOML is quoted.
+        None => match line.strip_prefix(&format!("{:?}", key)) {
+            Some(rest) => rest,
+            None => return false,
+        },
+    };
+    rest.trim_start().starts_with('=')
+}
+
+/// Returns the 1-based number of the first line that satisfies `pred`.
+fn find_line<F: Fn(&str) -> bool>(cont: &str, pred: F) -> Option<usize> {
+    cont.lines().position(pred).map(|i| i + 1)
+}
+
+fn location(path: &Path, line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!("{:?} line {}", path, line),
+        None => format!("{:?}", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_load() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        let cont = "\
+            [env]\n\
+            JAVA_HOME = \"/opt/jdk\"\n\
+            \n\
+            [path]\n\
+            prepend = [\"/opt/jdk/bin\"]\n\
+            append = [\"/mnt/c/Windows/System32\"]\n\
+        ";
+        tmp.write_all(cont.as_bytes()).unwrap();
+        let config = EnvConfigFile::load(tmp.path()).unwrap();
+        assert_eq!(
+            Some("/opt/jdk"),
+            config.envs.get("JAVA_HOME").map(|v| v.as_str())
+        );
+        assert_eq!(vec!["/opt/jdk/bin".to_owned()], config.prepend_paths);
+        assert_eq!(
+            vec!["/mnt/c/Windows/System32".to_owned()],
+            config.append_paths
+        );
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let path = Path::new("env.toml");
+        assert!(EnvConfigFile::parse(path, "[enviroment]\nFOO = \"foo\"\n").is_err());
+        assert!(EnvConfigFile::parse(path, "[path]\nprepand = [\"/a\"]\n").is_err());
+
+        let err = EnvConfigFile::parse(path, "[env]\nFOO = \"foo\"\n\"1BAD\" = \"bad\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 3") && err.contains("1BAD"), "{}", err);
+
+        let err = EnvConfigFile::parse(path, "[path]\nappend = [\n  \"/a\",\n  \"b\",\n]\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 4") && err.contains("\"b\""), "{}", err);
+    }
+
+    #[test]
+    fn test_apply_to_env_file() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        writeln!(&mut tmp, "JAVA_OPTS=-Xss1m").unwrap();
+        let mut env_file = EnvFile::open(tmp.path()).unwrap();
+
+        let mut config = EnvConfig::default();
+        config
+            .envs
+            .insert("JAVA_OPTS".to_owned(), "${env:JAVA_OPTS} -Xmx1g".to_owned());
+        config.prepend_paths = vec!["/opt/jdk/bin".to_owned()];
+        apply_to_env_file(&config, &mut env_file).unwrap();
+        assert_eq!(
+            Some("-Xss1m -Xmx1g"),
+            env_file.get_env("JAVA_OPTS").as_deref()
+        );
+        assert!(env_file
+            .get_env("PATH")
+            .unwrap()
+            .starts_with("/opt/jdk/bin:"));
+
+        config
+            .envs
+            .insert("BAD".to_owned(), "${env:NOT_DEFINED}".to_owned());
+        assert!(apply_to_env_file(&config, &mut env_file).is_err());
+
+        // A value rejected after the others are put leaves the file as it was.
+        let mut env_file = EnvFile::open(tmp.path()).unwrap();
+        config.envs.remove("BAD");
+        config
+            .envs
+            .insert("SLASHED".to_owned(), "back\\slash".to_owned());
+        assert!(apply_to_env_file(&config, &mut env_file).is_err());
+        assert_eq!("JAVA_OPTS=-Xss1m\n", env_file.render());
+        assert!(!env_file.is_dirty());
+    }
+
+    #[test]
+    fn test_apply_to_shell_script() {
+        let mut config = EnvConfig::default();
+        config
+            .envs
+            .insert("JAVA_HOME".to_owned(), "/opt/jdk".to_owned());
+        config.append_paths = vec!["/mnt/c/Windows/System32".to_owned()];
+        let mut script = EnvShellScript::new();
+        script
+            .put_env("EXISTING".to_owned(), "existing".to_owned())
+            .unwrap();
+        apply_to_shell_script(&config, &mut script).unwrap();
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        script.write(tmp.path()).unwrap();
+        let cont = std::fs::read_to_string(tmp.path()).unwrap();
+        assert!(cont.contains("export EXISTING='existing'"));
+        assert!(cont.contains("export JAVA_HOME='/opt/jdk'"));
+        assert!(cont.contains("__CANDIDATE_PATHS='/mnt/c/Windows/System32:'"));
+
+        config
+            .envs
+            .insert("REF".to_owned(), "${env:HOME}".to_owned());
+        assert!(apply_to_shell_script(&config, &mut script).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::envfile::{unquote_path_element, EnvFile, PathVariable};
+use crate::passwd::PasswdFile;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A finding of EnvDoctor. `code` is stable so that scripts can match on it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    pub remediation: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorReport {
+    pub root: PathBuf,
+    pub user: Option<String>,
+    /// Sorted from the most severe one.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl DoctorReport {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    pub fn codes(&self) -> Vec<&'static str> {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.code)
+            .collect()
+    }
+
+    pub fn to_text(&self) -> String {
+        if self.diagnostics.is_empty() {
+            return format!("No problems found in {:?}.\n", &self.root);
+        }
+        let mut text = String::new();
+        for diagnostic in &self.diagnostics {
+            text += &format!(
+                "{}[{}]: {}\n  = help: {}\n",
+                diagnostic.severity.as_str(),
+                diagnostic.code,
+                diagnostic.message,
+                diagnostic.remediation
+            );
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).with_context(|| "Failed to serialize the report.")
+    }
+}
+
+/// EnvDoctor inspects how the environment variables are set up in a distro whose root
+/// directory is `root`, so that `distrod env doctor` can explain why a variable doesn't
+/// show up as expected.
+pub struct EnvDoctor {
+    root: PathBuf,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl EnvDoctor {
+    pub fn run<P: AsRef<Path>>(root: P, user: Option<&str>) -> DoctorReport {
+        let mut doctor = EnvDoctor {
+            root: root.as_ref().to_owned(),
+            diagnostics: vec![],
+        };
+        doctor.check_env_file();
+        if let Some(user) = user {
+            doctor.check_user(user);
+        }
+
+        let mut diagnostics = doctor.diagnostics;
+        diagnostics.sort_by(|a, b| b.severity.cmp(&a.severity).then(a.code.cmp(b.code)));
+        DoctorReport {
+            root: doctor.root,
+            user: user.map(|user| user.to_owned()),
+            diagnostics,
+        }
+    }
+
+    fn report(
+        &mut self,
+        code: &'static str,
+        severity: Severity,
+        message: String,
+        remediation: &str,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            code,
+            severity,
+            message,
+            remediation: remediation.to_owned(),
+        });
+    }
+
+    fn path_in_root(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn check_env_file(&mut self) {
+        let env_file_path = self.path_in_root("/etc/environment");
+        let env_file = match EnvFile::open(&env_file_path) {
+            Ok(env_file) => env_file,
+            Err(e) => {
+                self.report(
+                    "ENVFILE001",
+                    Severity::Error,
+                    format!("Failed to load {:?}: {:?}", &env_file_path, e),
+                    "Make /etc/environment a readable regular file so that pam_env.so and distrod can load it.",
+                );
+                return;
+            }
+        };
+
+        for key in env_file.duplicated_keys() {
+            self.report(
+                "ENVFILE002",
+                Severity::Warning,
+                format!("{} is defined more than once in /etc/environment.", key),
+                "Only the last definition takes effect; remove the others.",
+            );
+        }
+        for key in env_file.keys() {
+            if env_file.get_env_raw(key).is_none() {
+                self.report(
+                    "ENVFILE003",
+                    Severity::Warning,
+                    format!("The value of {} in /etc/environment is not valid UTF-8.", key),
+                    "distrod keeps such a value as is but refuses to edit it with EnvFile::put_list_env.",
+                );
+            }
+        }
+
+        match env_file.get_env_raw("PATH") {
+            Some(path) => self.check_path(path),
+            None if env_file.get_env_bytes("PATH").is_none() => self.report(
+                "PATH001",
+                Severity::Info,
+                "PATH is not defined in /etc/environment.".to_owned(),
+                "EnvFile::put_path will extend the default PATH of Ubuntu when distrod adds a path.",
+            ),
+            // Already reported as ENVFILE003.
+            None => {}
+        }
+    }
+
+    fn check_path(&mut self, path: &str) {
+        let path_variable = PathVariable::parse(path);
+        let mut seen = HashSet::new();
+        for elem in path_variable.iter() {
+            let elem = unquote_path_element(elem);
+            if elem.is_empty() {
+                self.report(
+                    "PATH002",
+                    Severity::Warning,
+                    "PATH has an empty element, which means the current directory.".to_owned(),
+                    "Remove the empty element (e.g. a leading, trailing or doubled colon).",
+                );
+                continue;
+            }
+            if !seen.insert(elem.clone()) {
+                self.report(
+                    "PATH005",
+                    Severity::Info,
+                    format!("{} appears more than once in PATH.", elem),
+                    "Remove the later occurrences with EnvFile::remove_path and put it back once.",
+                );
+                continue;
+            }
+            if !elem.starts_with('/') {
+                self.report(
+                    "PATH003",
+                    Severity::Warning,
+                    format!("PATH has a relative element {}.", elem),
+                    "Replace it with an absolute path; a relative one depends on the current directory.",
+                );
+                continue;
+            }
+            if !self.path_in_root(&elem).is_dir() {
+                self.report(
+                    "PATH004",
+                    Severity::Warning,
+                    format!("{} in PATH doesn't exist in the distro.", elem),
+                    "Remove the stale element with EnvFile::remove_path.",
+                );
+            }
+        }
+    }
+
+    fn check_user(&mut self, user: &str) {
+        let passwd_path = self.path_in_root("/etc/passwd");
+        let shell = PasswdFile::open(&passwd_path).and_then(|mut passwd_file| {
+            Ok(passwd_file
+                .get_ent_by_name(user)?
+                .map(|ent| ent.shell.to_owned()))
+        });
+        let shell = match shell {
+            Ok(Some(shell)) => shell,
+            Ok(None) => {
+                self.report(
+                    "USER001",
+                    Severity::Error,
+                    format!("The user {} doesn't exist in {:?}.", user, &passwd_path),
+                    "Run the doctor for an existing user.",
+                );
+                return;
+            }
+            Err(e) => {
+                self.report(
+                    "USER001",
+                    Severity::Error,
+                    format!("Failed to look up the user {}: {:?}", user, e),
+                    "Make /etc/passwd readable.",
+                );
+                return;
+            }
+        };
+
+        let shell_name = shell.rsplit('/').next().unwrap_or_default();
+        if ["fish", "csh", "tcsh", "pwsh"].contains(&shell_name) {
+            self.report(
+                "USER002",
+                Severity::Info,
+                format!(
+                    "The login shell of {} is {}, which doesn't source POSIX shell scripts.",
+                    user, shell
+                ),
+                "Rely on /etc/environment, which pam_env.so applies regardless of the shell.",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn make_broken_root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::create_dir_all(root.path().join("usr/bin")).unwrap();
+        fs::write(
+            root.path().join("etc/environment"),
+            b"PATH=\"/usr/bin:/nonexistent:bin::/usr/bin\"\nLANG=C\nLANG=C.UTF-8\nJUNK=\xff\n",
+        )
+        .unwrap();
+        fs::write(
+            root.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\nfishy:x:1000:1000:,,,:/home/fishy:/usr/bin/fish\n",
+        )
+        .unwrap();
+        root
+    }
+
+    #[test]
+    fn test_broken_root() {
+        let root = make_broken_root();
+        let report = EnvDoctor::run(root.path(), Some("fishy"));
+        assert_eq!(
+            vec![
+                "ENVFILE002",
+                "ENVFILE003",
+                "PATH002",
+                "PATH003",
+                "PATH004",
+                "PATH005",
+                "USER002"
+            ],
+            report.codes()
+        );
+        assert!(!report.has_errors());
+        assert!(report
+            .to_text()
+            .contains("warning[PATH004]: /nonexistent in PATH"));
+        assert!(report.to_json().is_ok());
+
+        let report = EnvDoctor::run(root.path(), Some("nobody"));
+        assert_eq!(Some(&"USER001"), report.codes().first());
+        assert!(report.has_errors());
+    }
+
+    #[test]
+    fn test_healthy_root() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::create_dir_all(root.path().join("usr/bin")).unwrap();
+        fs::write(root.path().join("etc/environment"), "PATH=\"/usr/bin\"\n").unwrap();
+        let report = EnvDoctor::run(root.path(), None);
+        assert!(report.diagnostics.is_empty());
+        assert!(report.to_text().starts_with("No problems found"));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::envfile::{EnvFile, PathVariable, QuoteStyle};
+
+/// EnvFileSet merges a directory of fragments, `*.conf` files in the format of
+/// /etc/environment, so that they can be flattened into /etc/environment for pam_env.so.
+/// Fragments are read in the lexical order of their names. A later fragment overrides the
+/// variables of the earlier ones, except for the PATH-like ones, whose elements are
+/// concatenated without duplicates.
+#[derive(Debug, Clone)]
+pub struct EnvFileSet {
+    pub dir: PathBuf,
+    // The merged variables in the order they first appear.
+    envs: Vec<MergedEnv>,
+    warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct MergedEnv {
+    key: String,
+    value: String,
+    // The names of the fragments that make up the value.
+    sources: Vec<String>,
+}
+
+impl EnvFileSet {
+    pub fn open_dir(dir: &Path) -> Result<EnvFileSet> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))? {
+            let entry = entry.with_context(|| format!("Failed to read {:?}", dir))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".conf") && entry.path().is_file() {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        let mut set = EnvFileSet {
+            dir: dir.to_owned(),
+            envs: vec![],
+            warnings: vec![],
+        };
+        for name in names {
+            match EnvFile::open(dir.join(&name)) {
+                Ok(fragment) => set.merge_fragment(&name, &fragment),
+                Err(e) => set
+                    .warnings
+                    .push(format!("Skipped {} which can't be loaded: {:?}", name, e)),
+            }
+        }
+        Ok(set)
+    }
+
+    fn merge_fragment(&mut self, name: &str, fragment: &EnvFile) {
+        let keys = fragment.keys();
+        let invalid: Vec<_> = keys
+            .iter()
+            .filter(|key| fragment.get_env(key).is_none())
+            .collect();
+        if !invalid.is_empty() {
+            self.warnings.push(format!(
+                "Skipped {} since the values of {:?} are not valid UTF-8.",
+                name, invalid
+            ));
+            return;
+        }
+        for key in keys {
+            let value = fragment.get_env(key).expect("checked above");
+            let merged = match self.envs.iter_mut().find(|env| env.key == key) {
+                Some(merged) => merged,
+                None => {
+                    self.envs.push(MergedEnv {
+                        key: key.to_owned(),
+                        value,
+                        sources: vec![name.to_owned()],
+                    });
+                    continue;
+                }
+            };
+            if is_path_like(key) {
+                merged.value = concat_paths(&merged.value, &value);
+            } else {
+                merged.value = value;
+                merged.sources.clear();
+            }
+            if !merged.sources.iter().any(|source| source == name) {
+                merged.sources.push(name.to_owned());
+            }
+        }
+    }
+
+    /// The problems found in the fragments, each of which was skipped as a whole.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn get_env(&self, key: &str) -> Option<&str> {
+        self.envs
+            .iter()
+            .find(|env| env.key == key)
+            .map(|env| env.value.as_str())
+    }
+
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.envs
+            .iter()
+            .map(|env| (env.key.clone(), env.value.clone()))
+            .collect()
+    }
+
+    /// Put the merged variables to `target`, each annotated with the fragments it comes from,
+    /// like `PATH='/usr/bin:/opt/bin' # from 10-base.conf, 20-opt.conf`. Variables that are
+    /// not in any fragment are left alone.
+    pub fn flatten_into(&self, target: &mut EnvFile) -> Result<()> {
+        for env in &self.envs {
+            // Single quotes can't keep a backslash from pam_env.so.
+            let style = if env.value.contains('\\') {
+                QuoteStyle::Double
+            } else {
+                QuoteStyle::PreserveExisting
+            };
+            target.put_env_with_style(env.key.clone(), env.value.clone(), style)?;
+            target.annotate(&env.key, &format!("from {}", env.sources.join(", ")))?;
+        }
+        Ok(())
+    }
+}
+
+fn is_path_like(key: &str) -> bool {
+    key.ends_with("PATH") || key.ends_with("_DIRS")
+}
+
+fn concat_paths(former: &str, latter: &str) -> String {
+    let former_variable = PathVariable::parse(former);
+    let mut seen = HashSet::new();
+    let elems: Vec<_> = former_variable
+        .iter()
+        .chain(
+            PathVariable::parse(latter)
+                .iter()
+                .filter(|elem| !former_variable.contains(elem)),
+        )
+        .filter(|elem| seen.insert(elem.to_string()))
+        .map(|elem| elem.to_owned())
+        .collect();
+    elems.join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten_into() {
+        let dir = tempfile::tempdir().unwrap();
+        let fragments = dir.path().join("environment.d");
+        std::fs::create_dir(&fragments).unwrap();
+        std::fs::write(
+            fragments.join("10-base.conf"),
+            "PATH=/usr/bin:/bin\nLANG=C\nEDITOR=vi\n",
+        )
+        .unwrap();
+        std::fs::write(
+            fragments.join("20-tools.conf"),
+            "# Tools\nPATH=\"/opt/tools/bin:/usr/bin\"\nEDITOR=vim\n",
+        )
+        .unwrap();
+        std::fs::write(
+            fragments.join("30-local.conf"),
+            "LANG=C.UTF-8\nPATH=/usr/local/bin\nXDG_DATA_DIRS=/usr/share\n",
+        )
+        .unwrap();
+        std::fs::write(fragments.join("40-broken.conf"), b"LANG=\xff\n").unwrap();
+        std::fs::write(fragments.join("README"), "EDITOR=nano\n").unwrap();
+
+        let set = EnvFileSet::open_dir(&fragments).unwrap();
+        assert_eq!(1, set.warnings().len());
+        assert!(set.warnings()[0].contains("40-broken.conf"));
+        assert_eq!(
+            Some("/usr/bin:/bin:/opt/tools/bin:/usr/local/bin"),
+            set.get_env("PATH")
+        );
+        assert_eq!(Some("C.UTF-8"), set.get_env("LANG"));
+        assert_eq!(Some("vim"), set.get_env("EDITOR"));
+        assert_eq!(4, set.to_map().len());
+
+        let path = dir.path().join("environment");
+        std::fs::write(&path, "FOO=bar\nLANG=C\n").unwrap();
+        let mut env_file = EnvFile::open(&path).unwrap();
+        set.flatten_into(&mut env_file).unwrap();
+        env_file.write().unwrap();
+        assert_eq!(
+            "FOO=bar\n\
+             LANG='C.UTF-8' # from 30-local.conf\n\
+             PATH='/usr/bin:/bin:/opt/tools/bin:/usr/local/bin' # from 10-base.conf, 20-tools.conf, 30-local.conf\n\
+             EDITOR='vim' # from 20-tools.conf\n\
+             XDG_DATA_DIRS='/usr/share' # from 30-local.conf\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::envfile::{
+    EnvFile, EnvFileOptions, EnvShellScript, PathPolicy, PathVariable, SecretGuard,
+};
+
+/// What redact_secrets puts in place of a value that looks like a secret.
+pub const REDACTED: &str = "<redacted>";
+
+/// A snapshot of how the environment is set up, which `distrod config export` prints so
+/// that users can share it when they ask for help, instead of copying /etc/environment and
+/// the generated script by hand.
+#[derive(Debug, Clone, Serialize)]
+pub struct EnvReport {
+    pub env_file: PathBuf,
+    /// In the order of the effective definitions.
+    pub variables: Vec<VariableReport>,
+    /// The elements of PATH of the env file, unquoted.
+    pub path: Vec<PathElementReport>,
+    pub lint: Vec<LintReport>,
+    /// None if no script is given or it doesn't exist.
+    pub shell_script: Option<ShellScriptReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VariableReport {
+    pub key: String,
+    /// As it's written in the file, with the quotes.
+    pub raw: String,
+    /// As pam_env.so sets it.
+    pub value: String,
+    /// The 1-based number of the line of the effective definition.
+    pub line_number: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PathElementReport {
+    pub path: String,
+    /// Whether it's under a drive of Windows mounted by WSL, such as /mnt/c.
+    pub windows: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LintReport {
+    pub line_number: usize,
+    /// The name of the LintCategory, e.g. "SpaceAroundEquals".
+    pub category: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShellScriptReport {
+    pub path: PathBuf,
+    /// Sorted by key.
+    pub envs: Vec<ScriptEnvReport>,
+    /// In the order the script puts them.
+    pub paths: Vec<ScriptPathReport>,
+    /// The variables of the script whose values the env file doesn't agree on.
+    pub drift: Vec<DriftReport>,
+    /// The paths of the script that PATH of the env file doesn't have.
+    pub paths_not_in_env_file: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptEnvReport {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptPathReport {
+    pub path: String,
+    pub prepends: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DriftReport {
+    pub key: String,
+    /// None if the env file doesn't define it.
+    pub env_file_value: Option<String>,
+    pub script_value: String,
+}
+
+impl EnvReport {
+    /// The script at `shell_script_path` has to be one EnvShellScript::load understands.
+    pub fn collect(env_file: &EnvFile, shell_script_path: Option<&Path>) -> Result<EnvReport> {
+        let variables = env_file
+            .keys()
+            .into_iter()
+            .map(|key| VariableReport {
+                key: key.to_owned(),
+                raw: String::from_utf8_lossy(env_file.get_env_bytes(key).unwrap_or_default())
+                    .into_owned(),
+                value: env_file.logical_value(key).unwrap_or_default(),
+                line_number: env_file.line_number_of(key).unwrap_or_default(),
+            })
+            .collect();
+        let policy = PathPolicy::default();
+        let path = env_file
+            .logical_value("PATH")
+            .map(|value| {
+                value
+                    .split(':')
+                    .map(|path| PathElementReport {
+                        path: path.to_owned(),
+                        windows: policy.is_windows_path(path),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let lint = env_file
+            .lint()
+            .into_iter()
+            .map(|warning| LintReport {
+                line_number: warning.line_number,
+                category: format!("{:?}", warning.category),
+                text: warning.text,
+            })
+            .collect();
+        let shell_script = match shell_script_path {
+            Some(path) if path.exists() => Some(report_shell_script(env_file, path)?),
+            _ => None,
+        };
+        Ok(EnvReport {
+            env_file: env_file.file_path.clone(),
+            variables,
+            path,
+            lint,
+            shell_script,
+        })
+    }
+
+    pub fn to_json_pretty(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).with_context(|| "Failed to serialize the report.")
+    }
+
+    /// Replace the values that look like secrets with REDACTED, by the same rules as
+    /// SecretPolicy of EnvFileOptions with its default patterns, before the report is
+    /// shared. Returns the number of the values replaced.
+    pub fn redact_secrets(&mut self) -> usize {
+        let guard = SecretGuard::new(&EnvFileOptions::default())
+            .expect("the default patterns of secret keys are valid");
+        let is_secret = |key: &str, value: &str| guard.inspect(key, value).is_some();
+        let mut count = 0;
+        for variable in &mut self.variables {
+            if is_secret(&variable.key, &variable.value) {
+                variable.raw = REDACTED.to_owned();
+                variable.value = REDACTED.to_owned();
+                count += 1;
+            }
+        }
+        if let Some(ref mut script) = self.shell_script {
+            for env in &mut script.envs {
+                if is_secret(&env.key, &env.value) {
+                    env.value = REDACTED.to_owned();
+                    count += 1;
+                }
+            }
+            for drift in &mut script.drift {
+                if is_secret(&drift.key, &drift.script_value) {
+                    drift.script_value = REDACTED.to_owned();
+                    count += 1;
+                }
+                if let Some(ref mut value) = drift.env_file_value {
+                    if is_secret(&drift.key, value) {
+                        *value = REDACTED.to_owned();
+                        count += 1;
+                    }
+                }
+            }
+        }
+        // The lines lint warns about are shown as they are, values and all.
+        for lint in &mut self.lint {
+            if let Some(redacted) = redact_line(&lint.text, is_secret) {
+                lint.text = redacted;
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+/// Returns `line` with the value replaced with REDACTED if `is_secret` tells it's a secret,
+/// keeping what's before the value. The key is the first word before `=`, so that
+/// `export KEY = value` and `KEY DEFAULT=value` are redacted as well. A line without `=`,
+/// such as a token pasted alone, is checked as a value of no key.
+fn redact_line<F>(line: &str, is_secret: F) -> Option<String>
+where
+    F: Fn(&str, &str) -> bool,
+{
+    let (key, kept, value) = match line.find('=') {
+        Some(i) => {
+            let before = line[..i].trim_start();
+            let key = before.strip_prefix("export ").unwrap_or(before);
+            let value = line[i + 1..].trim_start();
+            let kept = &line[..line.len() - value.len()];
+            (
+                key.split_whitespace().next().unwrap_or_default(),
+                kept,
+                value,
+            )
+        }
+        None => ("", "", line.trim()),
+    };
+    if !is_secret(key, value.trim_end()) {
+        return None;
+    }
+    Some(format!("{}{}", kept, REDACTED))
+}
+
+fn report_shell_script(env_file: &EnvFile, path: &Path) -> Result<ShellScriptReport> {
+    let script = EnvShellScript::load(path)?;
+    let envs: Vec<_> = script
+        .envs()
+        .map(|(key, value)| ScriptEnvReport {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+        .collect();
+    let drift = envs
+        .iter()
+        .filter_map(|env| {
+            let env_file_value = env_file.get_env(&env.key);
+            if env_file_value.as_deref() == Some(env.value.as_str()) {
+                return None;
+            }
+            Some(DriftReport {
+                key: env.key.clone(),
+                env_file_value,
+                script_value: env.value.clone(),
+            })
+        })
+        .collect();
+    let env_file_path = env_file.get_env_raw("PATH").unwrap_or_default();
+    let env_file_path = PathVariable::parse(env_file_path);
+    let paths: Vec<_> = script
+        .paths()
+        .map(|(path, prepends)| ScriptPathReport {
+            path: path.to_owned(),
+            prepends,
+        })
+        .collect();
+    let paths_not_in_env_file = paths
+        .iter()
+        .filter(|path| !env_file_path.contains(&path.path))
+        .map(|path| path.path.clone())
+        .collect();
+    Ok(ShellScriptReport {
+        path: path.to_owned(),
+        envs,
+        paths,
+        drift,
+        paths_not_in_env_file,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE_DIR: &str = "tests/resources/env_report";
+
+    #[test]
+    fn test_report_of_fixtures() {
+        let env_file = EnvFile::parse_bytes(
+            include_bytes!("../tests/resources/env_report/environment"),
+            Some(PathBuf::from("/etc/environment")),
+        )
+        .unwrap();
+        let script_path = Path::new(FIXTURE_DIR).join("distrod_env.sh");
+        let mut report = EnvReport::collect(&env_file, Some(&script_path)).unwrap();
+        assert_eq!(
+            include_str!("../tests/resources/env_report/report.json"),
+            report.to_json_pretty().unwrap() + "\n"
+        );
+
+        assert_eq!(4, report.redact_secrets());
+        assert_eq!(
+            include_str!("../tests/resources/env_report/report_redacted.json"),
+            report.to_json_pretty().unwrap() + "\n"
+        );
+    }
+
+    #[test]
+    fn test_report_without_script() {
+        let env_file = EnvFile::parse_str("LANG=C\n").unwrap();
+        let report =
+            EnvReport::collect(&env_file, Some(&Path::new(FIXTURE_DIR).join("missing.sh")))
+                .unwrap();
+        assert!(report.shell_script.is_none());
+        assert!(report.path.is_empty());
+        assert_eq!(1, report.variables[0].line_number);
+    }
+}
@@ -0,0 +1,308 @@
+use anyhow::Result;
+
+use crate::envfile::EnvFile;
+
+/// What the values of the variables distrod manages have to look like, so that a typo in a
+/// key such as DISTROD_DEFALT_USER or a bad value is caught when it's written instead of
+/// being silently ignored. The variables that no rule or prefix of the schema covers are
+/// never checked.
+#[derive(Debug, Clone, Default)]
+pub struct EnvSchema {
+    keys: Vec<(String, Validator)>,
+    prefixes: Vec<(String, UnknownPolicy)>,
+}
+
+/// How the value of a variable is checked.
+#[derive(Debug, Clone)]
+pub enum Validator {
+    /// A path starting with `/`.
+    AbsolutePath,
+    /// A user name portable across POSIX systems: the letters, digits, `.`, `_` and `-` of
+    /// ASCII, not starting with `-`, and up to 32 characters as useradd allows.
+    Username,
+    /// One of `true`, `false`, `1` and `0`.
+    Boolean,
+    /// One of the values, compared exactly.
+    Enum(Vec<String>),
+    /// A value the regular expression matches. Anchor it with `^` and `$` to match the whole
+    /// value.
+    Regex(regex::Regex),
+}
+
+/// What the schema does with a key that starts with a prefix but has no rule of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownPolicy {
+    Allow,
+    Reject,
+}
+
+/// A variable that the schema doesn't accept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    pub key: String,
+    pub value: String,
+    /// What was expected, e.g. "an absolute path".
+    pub expected: String,
+}
+
+impl std::fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}={:?} is not valid: expected {}.",
+            self.key, self.value, self.expected
+        )
+    }
+}
+
+impl std::error::Error for SchemaViolation {}
+
+#[derive(Debug, Clone, Default)]
+pub struct EnvSchemaBuilder {
+    schema: EnvSchema,
+}
+
+impl EnvSchemaBuilder {
+    /// Check the value of `key` with `validator`. A key given again replaces the rule.
+    pub fn key(mut self, key: &str, validator: Validator) -> Self {
+        self.schema.keys.retain(|(k, _)| k != key);
+        self.schema.keys.push((key.to_owned(), validator));
+        self
+    }
+
+    /// Decide what to do with the keys starting with `prefix` that have no rule.
+    pub fn unknown_prefix_policy(mut self, prefix: &str, policy: UnknownPolicy) -> Self {
+        self.schema.prefixes.retain(|(p, _)| p != prefix);
+        self.schema.prefixes.push((prefix.to_owned(), policy));
+        self
+    }
+
+    pub fn build(self) -> EnvSchema {
+        self.schema
+    }
+}
+
+impl EnvSchema {
+    pub fn builder() -> EnvSchemaBuilder {
+        EnvSchemaBuilder::default()
+    }
+
+    /// Returns None if the schema accepts `value` for `key`.
+    pub fn check(&self, key: &str, value: &str) -> Option<SchemaViolation> {
+        let expected = match self.keys.iter().find(|(k, _)| k == key) {
+            Some((_, validator)) => validator.check(value)?,
+            None => {
+                let (prefix, _) = self.prefixes.iter().find(|(prefix, policy)| {
+                    *policy == UnknownPolicy::Reject && key.starts_with(prefix.as_str())
+                })?;
+                self.unknown_key_expectation(prefix)
+            }
+        };
+        Some(SchemaViolation {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            expected,
+        })
+    }
+
+    fn unknown_key_expectation(&self, prefix: &str) -> String {
+        let known: Vec<_> = self
+            .keys
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        if known.is_empty() {
+            return format!("no variable starting with {}", prefix);
+        }
+        format!(
+            "one of the known variables starting with {}: {}",
+            prefix,
+            known.join(", ")
+        )
+    }
+}
+
+impl Validator {
+    /// Returns what was expected if `value` is not valid.
+    fn check(&self, value: &str) -> Option<String> {
+        let valid = match self {
+            Validator::AbsolutePath => value.starts_with('/'),
+            Validator::Username => is_portable_username(value),
+            Validator::Boolean => ["true", "false", "1", "0"].contains(&value),
+            Validator::Enum(values) => values.iter().any(|v| v == value),
+            Validator::Regex(regex) => regex.is_match(value),
+        };
+        if valid {
+            return None;
+        }
+        Some(match self {
+            Validator::AbsolutePath => "an absolute path".to_owned(),
+            Validator::Username => {
+                "a user name of letters, digits, `.`, `_` and `-`, not starting with `-`".to_owned()
+            }
+            Validator::Boolean => "one of true, false, 1 and 0".to_owned(),
+            Validator::Enum(values) => format!("one of {}", values.join(", ")),
+            Validator::Regex(regex) => format!("a value matching {}", regex.as_str()),
+        })
+    }
+}
+
+fn is_portable_username(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && !name.starts_with('-')
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'.' || c == b'_' || c == b'-')
+}
+
+impl EnvFile {
+    /// Like put_env, but fails with SchemaViolation if `schema` doesn't accept the variable,
+    /// in which case the file is unchanged.
+    pub fn put_env_checked(
+        &mut self,
+        schema: &EnvSchema,
+        key: String,
+        value: String,
+    ) -> Result<()> {
+        if let Some(violation) = schema.check(&key, &value) {
+            return Err(violation.into());
+        }
+        self.put_env(key, value)
+    }
+
+    /// Check the variables already in the file, e.g. written by hand, with their values as
+    /// pam_env.so sets them. The violations are in the order of the effective definitions.
+    pub fn validate_all(&self, schema: &EnvSchema) -> Vec<SchemaViolation> {
+        self.keys()
+            .into_iter()
+            .filter_map(|key| schema.check(key, &self.get_env(key)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distrod_schema() -> EnvSchema {
+        EnvSchema::builder()
+            .key("DISTROD_DEFAULT_USER", Validator::Username)
+            .key("DISTROD_RUN_DIR", Validator::AbsolutePath)
+            .key("DISTROD_SYSTEMD", Validator::Boolean)
+            .key(
+                "DISTROD_LOG_LEVEL",
+                Validator::Enum(vec!["error".to_owned(), "info".to_owned()]),
+            )
+            .key(
+                "DISTROD_PORT",
+                Validator::Regex(regex::Regex::new("^[0-9]+$").unwrap()),
+            )
+            .unknown_prefix_policy("DISTROD_", UnknownPolicy::Reject)
+            .build()
+    }
+
+    #[test]
+    fn test_validators() {
+        let schema = distrod_schema();
+        for (key, valid, invalid) in &[
+            (
+                "DISTROD_DEFAULT_USER",
+                &["alice", "_apt", "john.doe", "user-1"][..],
+                &["", "-alice", "al ice", "ユーザー", &"a".repeat(33)][..],
+            ),
+            (
+                "DISTROD_RUN_DIR",
+                &["/run/distrod", "/"][..],
+                &["run/distrod", "~/run", ""][..],
+            ),
+            (
+                "DISTROD_SYSTEMD",
+                &["true", "false", "1", "0"][..],
+                &["yes", "True", ""][..],
+            ),
+            (
+                "DISTROD_LOG_LEVEL",
+                &["error", "info"][..],
+                &["debug", "INFO"][..],
+            ),
+            ("DISTROD_PORT", &["8080"][..], &["80a", ""][..]),
+        ] {
+            for value in valid.iter() {
+                assert_eq!(None, schema.check(key, value), "{}={}", key, value);
+            }
+            for value in invalid.iter() {
+                let violation = schema.check(key, value).unwrap();
+                assert_eq!(*key, violation.key);
+                assert_eq!(*value, violation.value);
+            }
+        }
+        assert_eq!(
+            "DISTROD_SYSTEMD=\"yes\" is not valid: expected one of true, false, 1 and 0.",
+            schema.check("DISTROD_SYSTEMD", "yes").unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn test_put_env_checked() {
+        let schema = distrod_schema();
+        let mut env = EnvFile::parse_str("LANG=C\n").unwrap();
+        let err = env
+            .put_env_checked(
+                &schema,
+                "DISTROD_DEFALT_USER".to_owned(),
+                "alice".to_owned(),
+            )
+            .unwrap_err();
+        let violation = err.downcast_ref::<SchemaViolation>().unwrap();
+        assert_eq!("DISTROD_DEFALT_USER", violation.key);
+        assert!(
+            violation.expected.contains("DISTROD_DEFAULT_USER"),
+            "{}",
+            violation.expected
+        );
+        assert!(env
+            .put_env_checked(&schema, "DISTROD_RUN_DIR".to_owned(), "run".to_owned())
+            .is_err());
+        assert_eq!("LANG=C\n", env.render());
+
+        env.put_env_checked(
+            &schema,
+            "DISTROD_DEFAULT_USER".to_owned(),
+            "alice".to_owned(),
+        )
+        .unwrap();
+        // The keys without the prefix are never checked.
+        env.put_env_checked(&schema, "DISTRO_NAME".to_owned(), "-x".to_owned())
+            .unwrap();
+        env.put_env_checked(&schema, "EDITOR".to_owned(), "vim".to_owned())
+            .unwrap();
+        assert_eq!(
+            Some("alice"),
+            env.get_env("DISTROD_DEFAULT_USER").as_deref()
+        );
+
+        let allowed = EnvSchema::builder()
+            .unknown_prefix_policy("DISTROD_", UnknownPolicy::Allow)
+            .build();
+        env.put_env_checked(&allowed, "DISTROD_NEW".to_owned(), "1".to_owned())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_validate_all() {
+        let env = EnvFile::parse_str(
+            "DISTROD_RUN_DIR='run'\nLANG=C\nDISTROD_SYSTEMD=true\nDISTROD_TYPO=1\nUSER=-x\n",
+        )
+        .unwrap();
+        let violations = env.validate_all(&distrod_schema());
+        assert_eq!(
+            vec![("DISTROD_RUN_DIR", "run"), ("DISTROD_TYPO", "1")],
+            violations
+                .iter()
+                .map(|v| (v.key.as_str(), v.value.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,414 @@
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::envfile::{
+    create_tmp_file, finish_writing, mode_and_owner_of, replace_file, set_mode_and_owner,
+    sync_parent_dir, Durability, EnvFile, EnvModifier, EnvShellScript, FileStamp, Owner,
+    ShellFormat,
+};
+use crate::environment_d::EnvironmentDDropIn;
+
+/// EnvSync keeps the places distrod sets up the environment in agreement: /etc/environment
+/// for PAM sessions, the shell script for shells started without PAM, and optionally a
+/// drop-in of environment.d for systemd user services. A change is made to all of them or to
+/// none, each quoting the values in its own way, and commit() writes them together.
+pub struct EnvSync {
+    pub env_file: EnvFile,
+    pub shell_script: EnvShellScript,
+    pub environment_d: Option<EnvironmentDDropIn>,
+    shell_script_path: PathBuf,
+}
+
+/// A backend written to a temporary file next to it, to be renamed over it.
+struct StagedFile {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    mode: u32,
+    owner: Option<Owner>,
+    durability: Durability,
+    // What the backend had before, to put back if the commit fails. None if it didn't exist.
+    original: Option<Vec<u8>>,
+}
+
+impl EnvSync {
+    /// The shell script is loaded if it exists, so that what it already has is kept.
+    /// The drop-in of environment.d is managed only if `environment_d_path` is given.
+    pub fn open(
+        env_file_path: &Path,
+        shell_script_path: &Path,
+        environment_d_path: Option<&Path>,
+    ) -> Result<EnvSync> {
+        let shell_script = if shell_script_path.exists() {
+            EnvShellScript::load(shell_script_path)?
+        } else {
+            EnvShellScript::new()
+        };
+        Ok(EnvSync {
+            env_file: EnvFile::open(env_file_path)?,
+            shell_script,
+            environment_d: environment_d_path
+                .map(EnvironmentDDropIn::open)
+                .transpose()?,
+            shell_script_path: shell_script_path.to_owned(),
+        })
+    }
+
+    pub fn put_env(&mut self, key: &str, value: &str) -> Result<()> {
+        self.apply(|backend| backend.put_env(key, value))
+    }
+
+    pub fn put_path(&mut self, path: &str, prepends: bool) -> Result<()> {
+        self.apply(|backend| backend.put_path(path, prepends))
+    }
+
+    /// Make the change to copies of the backends, and take the copies only if every backend
+    /// accepts it.
+    fn apply<F>(&mut self, change: F) -> Result<()>
+    where
+        F: Fn(&mut dyn EnvModifier) -> Result<()>,
+    {
+        let mut env_file = self.env_file.clone();
+        change(&mut env_file)
+            .with_context(|| format!("Failed to update {:?}.", &env_file.file_path))?;
+        let mut shell_script = self.shell_script.clone();
+        change(&mut shell_script)
+            .with_context(|| format!("Failed to update {:?}.", &self.shell_script_path))?;
+        let environment_d = match self.environment_d {
+            Some(ref drop_in) => {
+                let mut drop_in = drop_in.clone();
+                change(&mut drop_in)
+                    .with_context(|| format!("Failed to update {:?}.", &drop_in.file_path))?;
+                Some(drop_in)
+            }
+            None => None,
+        };
+        self.env_file = env_file;
+        self.shell_script = shell_script;
+        self.environment_d = environment_d;
+        Ok(())
+    }
+
+    /// Write all the backends. Each one is written to a temporary file next to it first, with
+    /// the mode and the owner of the backend, and they are renamed over the backends only
+    /// after all of them are written. If a rename fails, the backends already renamed over
+    /// are put back as they were, so every backend is left as it was on an error. The env
+    /// file is locked as open_locked does during this, and nothing is written if someone else
+    /// has written it since it was read.
+    pub fn commit(&mut self) -> Result<()> {
+        let _lock = if self.env_file.is_locked() {
+            None
+        } else {
+            Some(self.env_file.lock_exclusive()?)
+        };
+        self.env_file.ensure_not_modified()?;
+        let env_file_cont = self.env_file.serialize_for_write()?;
+        let mut staged = vec![];
+        if let Err(e) = self.stage_all(&env_file_cont, &mut staged) {
+            for file in &staged {
+                let _ = std::fs::remove_file(&file.tmp_path);
+            }
+            return Err(e);
+        }
+        for (i, file) in staged.iter().enumerate() {
+            let result = std::fs::rename(&file.tmp_path, &file.path)
+                .with_context(|| {
+                    format!("Failed to rename {:?} to {:?}.", &file.tmp_path, &file.path)
+                })
+                .and_then(|_| sync_parent_dir(&file.path, file.durability));
+            if let Err(e) = result {
+                for file in &staged[i..] {
+                    let _ = std::fs::remove_file(&file.tmp_path);
+                }
+                for file in &staged[..i] {
+                    if let Err(e) = file.restore() {
+                        log::warn!("{:?}", e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        let stamp = FileStamp::of(&self.env_file.file_path);
+        self.env_file.mark_written(&env_file_cont, stamp);
+        Ok(())
+    }
+
+    fn stage_all(&self, env_file_cont: &[u8], staged: &mut Vec<StagedFile>) -> Result<()> {
+        let env_file_path = self.env_file.resolved_path();
+        let existing = std::fs::metadata(&env_file_path).ok();
+        let (mode, owner) = self.env_file.mode_and_owner_for(existing.as_ref(), None);
+        staged.push(stage(
+            &env_file_path,
+            env_file_cont,
+            (mode, owner),
+            self.env_file.durability(),
+        )?);
+        let script = self.shell_script.render_with_format(ShellFormat::Posix);
+        staged.push(stage(
+            &self.shell_script_path,
+            script.as_bytes(),
+            existing_mode_and_owner(&self.shell_script_path, 0o755),
+            self.shell_script.durability(),
+        )?);
+        if let Some(ref drop_in) = self.environment_d {
+            if let Some(dir) = drop_in.file_path.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {:?}.", dir))?;
+            }
+            staged.push(stage(
+                &drop_in.file_path,
+                drop_in.serialize().as_bytes(),
+                existing_mode_and_owner(&drop_in.file_path, 0o644),
+                Durability::default(),
+            )?);
+        }
+        Ok(())
+    }
+}
+
+impl EnvModifier for EnvSync {
+    fn put_env(&mut self, key: &str, value: &str) -> Result<()> {
+        EnvSync::put_env(self, key, value)
+    }
+
+    fn put_path(&mut self, path: &str, prepends: bool) -> Result<()> {
+        EnvSync::put_path(self, path, prepends)
+    }
+}
+
+/// The mode and the owner of `path` if it exists, or `default_mode` and the caller otherwise.
+fn existing_mode_and_owner(path: &Path, default_mode: u32) -> (u32, Option<Owner>) {
+    match std::fs::metadata(path) {
+        Ok(metadata) => {
+            let (mode, owner) = mode_and_owner_of(&metadata);
+            (mode, Some(owner))
+        }
+        Err(_) => (default_mode, None),
+    }
+}
+
+/// Write `cont` to a temporary file for `path` with `mode_and_owner`, and read what `path`
+/// has to put it back if the commit fails.
+fn stage(
+    path: &Path,
+    cont: &[u8],
+    (mode, owner): (u32, Option<Owner>),
+    durability: Durability,
+) -> Result<StagedFile> {
+    let original = match std::fs::read(path) {
+        Ok(original) => Some(original),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}.", path)),
+    };
+    let (file, tmp_path) = create_tmp_file(path)?;
+    let result = set_mode_and_owner(&file, &tmp_path, mode, owner).and_then(|_| {
+        let mut file = BufWriter::new(file);
+        file.write_all(cont)
+            .with_context(|| format!("Failed to write {:?}.", &tmp_path))?;
+        finish_writing(file, &tmp_path, durability)
+    });
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(StagedFile {
+        path: path.to_owned(),
+        tmp_path,
+        mode,
+        owner,
+        durability,
+        original,
+    })
+}
+
+impl StagedFile {
+    /// Put back what the backend had before it was renamed over.
+    fn restore(&self) -> Result<()> {
+        match self.original {
+            Some(ref original) => {
+                replace_file(&self.path, original, self.mode, self.owner, self.durability)
+            }
+            None => std::fs::remove_file(&self.path)
+                .with_context(|| format!("Failed to remove {:?}.", &self.path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envfile::EnvFileError;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn test_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file_path = dir.path().join("environment");
+        let script_path = dir.path().join("distrod_env.sh");
+        let drop_in_path = dir.path().join("environment.d").join("50-distrod.conf");
+        std::fs::write(&env_file_path, "LANG=C\n").unwrap();
+
+        let mut sync = EnvSync::open(&env_file_path, &script_path, Some(&drop_in_path)).unwrap();
+        sync.put_env("GREETING", "it's a test").unwrap();
+        sync.put_path("/opt/distrod/bin", true).unwrap();
+        // The environment file can't have NUL or a backslash, so no backend gets them.
+        assert!(sync.put_env("BAD", "nul\0").is_err());
+        assert!(sync.put_env("BAD", "a \\ b").is_err());
+        sync.commit().unwrap();
+
+        let env_file = EnvFile::open(&env_file_path).unwrap();
+        assert_eq!(Some("it's a test"), env_file.get_env("GREETING").as_deref());
+        assert!(env_file
+            .get_env("PATH")
+            .unwrap()
+            .starts_with("/opt/distrod/bin:"));
+        assert_eq!(None, env_file.get_env("BAD"));
+
+        let script = std::fs::read_to_string(&script_path).unwrap();
+        assert!(
+            script.contains("export GREETING='it'\"'\"'s a test'"),
+            "{}",
+            script
+        );
+        assert!(script.contains("__CANDIDATE_PATHS='/opt/distrod/bin:'"));
+        assert!(!script.contains("BAD"));
+        assert_eq!(
+            0o755,
+            std::fs::metadata(&script_path)
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        );
+
+        assert_eq!(
+            "GREETING=it\\'s a test\nPATH=/opt/distrod/bin:$PATH\n",
+            std::fs::read_to_string(&drop_in_path).unwrap()
+        );
+        assert_eq!(3, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_put_path_with_quote() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file_path = dir.path().join("environment");
+        let script_path = dir.path().join("distrod_env.sh");
+        std::fs::write(&env_file_path, "PATH=/usr/bin\n").unwrap();
+
+        let mut sync = EnvSync::open(&env_file_path, &script_path, None).unwrap();
+        let err = sync.put_path("/home/o'brien/bin", true).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<EnvFileError>(),
+            Some(EnvFileError::InvalidValue { key, .. }) if key == "PATH"
+        ));
+        sync.commit().unwrap();
+        assert_eq!(
+            "PATH=/usr/bin\n",
+            std::fs::read_to_string(&env_file_path).unwrap()
+        );
+        // The shell script, which could quote it, doesn't get it either.
+        assert!(!std::fs::read_to_string(&script_path)
+            .unwrap()
+            .contains("brien"));
+    }
+
+    #[test]
+    fn test_commit_keeps_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file_path = dir.path().join("environment");
+        let script_path = dir.path().join("distrod_env.sh");
+        std::fs::write(&env_file_path, "LANG=C\n").unwrap();
+        std::fs::set_permissions(&env_file_path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut sync = EnvSync::open(&env_file_path, &script_path, None).unwrap();
+        sync.put_env("EDITOR", "vim").unwrap();
+        sync.commit().unwrap();
+        let metadata = std::fs::metadata(&env_file_path).unwrap();
+        assert_eq!(0o600, metadata.permissions().mode() & 0o7777);
+        assert_eq!(nix::unistd::getuid().as_raw(), metadata.uid());
+    }
+
+    #[test]
+    fn test_commit_waits_for_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file_path = dir.path().join("environment");
+        let script_path = dir.path().join("distrod_env.sh");
+        std::fs::write(&env_file_path, "LANG=C\n").unwrap();
+        let mut sync = EnvSync::open(&env_file_path, &script_path, None).unwrap();
+        sync.put_env("EDITOR", "vim").unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let path = env_file_path.clone();
+        let writer = std::thread::spawn(move || {
+            let mut env_file = EnvFile::open_locked(&path).unwrap();
+            sender.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            env_file
+                .put_env("PAGER".to_owned(), "less".to_owned())
+                .unwrap();
+            env_file.write().unwrap();
+        });
+        receiver.recv().unwrap();
+        // The commit waits for the locked writer, and then sees its change instead of losing it.
+        assert!(sync.commit().is_err());
+        writer.join().unwrap();
+        let env_file = EnvFile::open(&env_file_path).unwrap();
+        assert_eq!(Some("less"), env_file.get_env("PAGER").as_deref());
+        assert!(!script_path.exists());
+    }
+
+    #[test]
+    fn test_failed_rename_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file_path = dir.path().join("environment");
+        let script_path = dir.path().join("distrod_env.sh");
+        let drop_in_path = dir.path().join("environment.d").join("50-distrod.conf");
+        std::fs::write(&env_file_path, "LANG=C\n").unwrap();
+
+        let mut sync = EnvSync::open(&env_file_path, &script_path, Some(&drop_in_path)).unwrap();
+        sync.put_env("EDITOR", "vim").unwrap();
+        // The drop-in is staged, but can't be renamed over a directory that isn't empty.
+        std::fs::create_dir_all(drop_in_path.join("dir")).unwrap();
+        assert!(sync.commit().is_err());
+        assert_eq!("LANG=C\n", std::fs::read_to_string(&env_file_path).unwrap());
+        assert!(!script_path.exists());
+        assert_eq!(
+            vec!["50-distrod.conf"],
+            std::fs::read_dir(drop_in_path.parent().unwrap())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_failed_commit_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file_path = dir.path().join("environment");
+        let script_path = dir.path().join("distrod_env.sh");
+        std::fs::write(&env_file_path, "LANG=C\n").unwrap();
+        let drop_in_dir = dir.path().join("environment.d");
+
+        let mut sync = EnvSync::open(
+            &env_file_path,
+            &script_path,
+            Some(&drop_in_dir.join("50-distrod.conf")),
+        )
+        .unwrap();
+        sync.put_env("EDITOR", "vim").unwrap();
+        // The drop-in can't be made since its directory is a regular file.
+        std::fs::write(&drop_in_dir, "").unwrap();
+        assert!(sync.commit().is_err());
+        assert_eq!("LANG=C\n", std::fs::read_to_string(&env_file_path).unwrap());
+        assert!(!script_path.exists());
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(vec!["environment", "environment.d"], names);
+    }
+}
@@ -0,0 +1,507 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::{Gid, Uid};
+
+use crate::envfile::{replace_file, validate_key, Durability, EnvModifier, EnvShellScript};
+
+/// The name of the drop-in distrod puts in /etc/environment.d or ~/.config/environment.d.
+pub const DISTROD_DROP_IN_NAME: &str = "50-distrod.conf";
+
+/// The name of the drop-in write_user_session_env writes, late enough in the order to
+/// override the drop-ins of the distro.
+pub const USER_SESSION_DROP_IN_NAME: &str = "90-distrod.conf";
+
+/// EnvironmentDDropIn edits a drop-in of environment.d(5), which systemd uses to set up the
+/// environment of user services. Values are written as they are without shell quoting, and
+/// `$VAR` in them are expanded by systemd. The values given through EnvModifier are literal, so
+/// quotes and backslashes in them are escaped, and the ones with `$` are rejected. Comments and
+/// unknown lines are kept.
+#[derive(Debug, Clone)]
+pub struct EnvironmentDDropIn {
+    pub file_path: PathBuf,
+    envs: HashMap<String, usize>,
+    lines: Vec<DropInLine>,
+}
+
+#[derive(Debug, Clone)]
+enum DropInLine {
+    Env { key: String, value: String },
+    Other(String),
+}
+
+impl EnvironmentDDropIn {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<EnvironmentDDropIn> {
+        let cont = match std::fs::read_to_string(path.as_ref()) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            cont => cont.with_context(|| format!("Failed to read {:?}", path.as_ref()))?,
+        };
+        Ok(EnvironmentDDropIn::parse(path.as_ref().to_owned(), &cont))
+    }
+
+    /// Like open, but fails if the drop-in is a symlink. Root opens one in a directory that
+    /// the user controls this way, who could otherwise have it read e.g. /etc/shadow.
+    fn open_no_follow(path: &Path) -> Result<EnvironmentDDropIn> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::fcntl::OFlag::O_NOFOLLOW.bits())
+            .open(path);
+        let mut cont = String::new();
+        match file {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            file => {
+                file.and_then(|mut file| file.read_to_string(&mut cont))
+                    .with_context(|| format!("Failed to read {:?}", path))?;
+            }
+        }
+        Ok(EnvironmentDDropIn::parse(path.to_owned(), &cont))
+    }
+
+    fn parse(file_path: PathBuf, cont: &str) -> EnvironmentDDropIn {
+        let mut drop_in = EnvironmentDDropIn {
+            file_path,
+            envs: HashMap::new(),
+            lines: vec![],
+        };
+        for line in cont.lines() {
+            let is_comment = line.trim_start().starts_with('#');
+            match line.split_once('=') {
+                Some((key, value)) if !is_comment && validate_key(key).is_ok() => {
+                    drop_in.envs.insert(key.to_owned(), drop_in.lines.len());
+                    drop_in.lines.push(DropInLine::Env {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    });
+                }
+                _ => drop_in.lines.push(DropInLine::Other(line.to_owned())),
+            }
+        }
+        drop_in
+    }
+
+    pub fn get_env(&self, key: &str) -> Option<&str> {
+        match self.lines[*self.envs.get(key)?] {
+            DropInLine::Env { ref value, .. } => Some(value),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn put_env(&mut self, key: String, value: String) {
+        // A newline would end the value and start another assignment.
+        assert!(!value.contains('\n'));
+        match self.envs.get(&key) {
+            Some(index) => match self.lines[*index] {
+                DropInLine::Env {
+                    value: ref mut v, ..
+                } => *v = value,
+                _ => unreachable!(),
+            },
+            None => {
+                self.lines.push(DropInLine::Env {
+                    key: key.clone(),
+                    value,
+                });
+                self.envs.insert(key, self.lines.len() - 1);
+            }
+        }
+    }
+
+    /// Returns false if `key` is not in the drop-in.
+    pub fn remove_env(&mut self, key: &str) -> bool {
+        let index = match self.envs.remove(key) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.lines.remove(index);
+        for i in self.envs.values_mut() {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+        true
+    }
+
+    /// Put a path to PATH, which extends the PATH systemd gives with a `$PATH` reference,
+    /// like `PATH=/new/bin:$PATH`, if PATH isn't set in the drop-in yet.
+    /// Paths that are already there are ignored.
+    pub fn put_path(&mut self, path: String, prepends: bool) {
+        assert!(!path.contains('\n') && !path.contains(':'));
+        let value = self.get_env("PATH").unwrap_or("$PATH");
+        let mut elems: Vec<_> = value.split(':').map(|elem| elem.to_owned()).collect();
+        if elems.contains(&path) {
+            return;
+        }
+        if prepends {
+            elems.insert(0, path);
+        } else {
+            elems.push(path);
+        }
+        self.put_env("PATH".to_owned(), elems.join(":"));
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut cont = String::new();
+        for line in &self.lines {
+            match line {
+                DropInLine::Env { key, value } => {
+                    cont.push_str(&format!("{}={}\n", key, value));
+                }
+                DropInLine::Other(line) => {
+                    cont.push_str(line);
+                    cont.push('\n');
+                }
+            }
+        }
+        cont
+    }
+
+    /// Write the drop-in, keeping its mode. It's replaced by renaming a new file over it, so
+    /// a symlink put in its place is replaced rather than written through.
+    pub fn write(&mut self) -> Result<()> {
+        self.write_with_owner(None)
+    }
+
+    /// Like write, but the new file is given to `owner` through its descriptor before it's
+    /// renamed into place.
+    fn write_with_owner(&mut self, owner: Option<(Uid, Gid)>) -> Result<()> {
+        if let Some(dir) = self.file_path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+        }
+        let mode = match std::fs::symlink_metadata(&self.file_path) {
+            Ok(metadata) if metadata.is_file() => metadata.permissions().mode() & 0o7777,
+            _ => 0o644,
+        };
+        replace_file(
+            &self.file_path,
+            self.serialize().as_bytes(),
+            mode,
+            owner,
+            Durability::default(),
+        )
+    }
+}
+
+impl EnvModifier for EnvironmentDDropIn {
+    fn put_env(&mut self, key: &str, value: &str) -> Result<()> {
+        let escaped = match escape_for_drop_in(value, false) {
+            Some(escaped) if validate_key(key).is_ok() => escaped,
+            _ => bail!("{}={:?} can't be put to {:?}.", key, value, &self.file_path),
+        };
+        EnvironmentDDropIn::put_env(self, key.to_owned(), escaped);
+        Ok(())
+    }
+
+    fn put_path(&mut self, path: &str, prepends: bool) -> Result<()> {
+        let escaped = match escape_for_drop_in(path, false) {
+            Some(escaped) if !path.contains(':') => escaped,
+            _ => bail!("{:?} can't be put to PATH in {:?}.", path, &self.file_path),
+        };
+        EnvironmentDDropIn::put_path(self, escaped, prepends);
+        Ok(())
+    }
+}
+
+/// Options of EnvShellScript::write_user_session_env.
+#[derive(Debug, Clone, Default)]
+pub struct UserSessionEnvOptions {
+    /// The owner given to the drop-in and the directories created for it, which should be the
+    /// user whose home directory it's in if the caller is root.
+    pub owner: Option<(Uid, Gid)>,
+}
+
+/// The result of EnvShellScript::write_user_session_env.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserEnvReport {
+    pub path: PathBuf,
+    /// The variables whose values in the drop-in have changed, sorted. systemd reads the
+    /// drop-in only when the user session starts, so a running session gets them only if
+    /// they are pushed with dbus-update-activation-environment.
+    pub changed_vars: Vec<String>,
+    /// The variables and paths left out since environment.d can't have them as they are.
+    pub skipped: Vec<String>,
+}
+
+impl UserEnvReport {
+    /// A POSIX shell snippet that pushes the changed variables to the D-Bus activation
+    /// environment and the systemd user manager. dbus-update-activation-environment takes the
+    /// values from its own environment, so it has to run after the shell script is sourced.
+    /// Empty if nothing has changed.
+    pub fn dbus_update_script(&self) -> String {
+        if self.changed_vars.is_empty() {
+            return String::new();
+        }
+        format!(
+            "if [ -n \"${{DBUS_SESSION_BUS_ADDRESS:-}}\" ] && command -v dbus-update-activation-environment >/dev/null 2>&1; then\n\
+             \x20   dbus-update-activation-environment --systemd {}\n\
+             fi\n",
+            self.changed_vars.join(" ")
+        )
+    }
+}
+
+impl EnvShellScript {
+    /// Write the variables and paths of the script to ~/.config/environment.d under `home`,
+    /// so that systemd user services and the graphical apps they start get them as well.
+    /// The variables the script unsets are removed from the drop-in, and the rest of what
+    /// the drop-in has is kept. Values put by put_env_expr are written as they are, since
+    /// systemd expands `$NAME` and `${NAME}` in the same way.
+    pub fn write_user_session_env(
+        &self,
+        home: &Path,
+        options: &UserSessionEnvOptions,
+    ) -> Result<UserEnvReport> {
+        let dir = home.join(".config").join("environment.d");
+        let path = dir.join(USER_SESSION_DROP_IN_NAME);
+        let mut drop_in = EnvironmentDDropIn::open_no_follow(&path)?;
+        let original = drop_in.clone();
+        let mut report = UserEnvReport {
+            path: path.clone(),
+            ..UserEnvReport::default()
+        };
+        for key in self.removed_env_keys() {
+            drop_in.remove_env(key);
+        }
+        for (key, value, is_expr) in self.env_values() {
+            match escape_for_drop_in(value, is_expr) {
+                Some(escaped) => drop_in.put_env(key.to_owned(), escaped),
+                None => report.skipped.push(key.to_owned()),
+            }
+        }
+        for (path, prepends) in self.path_values() {
+            match escape_for_drop_in(path, false) {
+                Some(escaped) if !path.contains(':') => drop_in.put_path(escaped, prepends),
+                _ => report.skipped.push(path.to_owned()),
+            }
+        }
+        let mut keys: Vec<_> = drop_in.envs.keys().collect();
+        keys.sort();
+        report.changed_vars = keys
+            .into_iter()
+            .filter(|key| original.get_env(key) != drop_in.get_env(key))
+            .cloned()
+            .collect();
+
+        let missing_dirs: Vec<_> = dir
+            .ancestors()
+            .take_while(|dir| !dir.exists())
+            .map(Path::to_owned)
+            .collect();
+        drop_in.write_with_owner(options.owner)?;
+        if let Some((uid, gid)) = options.owner {
+            // The user can swap a directory for a symlink, which chown(2) would follow.
+            for dir in missing_dirs.iter().rev() {
+                nix::unistd::fchownat(
+                    None,
+                    dir,
+                    Some(uid),
+                    Some(gid),
+                    nix::unistd::FchownatFlags::NoFollowSymlink,
+                )
+                .with_context(|| format!("Failed to chown {:?}.", dir))?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// `value` escaped so that systemd reads it back from environment.d as it is. systemd unquotes
+/// a value that starts with a quote and takes a backslash as an escape, so quotes and
+/// backslashes are escaped with a backslash. It expands `$` in values even if it's escaped,
+/// which is only wanted in an expression, so None for a literal value with `$`, and for a
+/// value with a newline.
+fn escape_for_drop_in(value: &str, is_expr: bool) -> Option<String> {
+    if value.contains('\n') || (!is_expr && value.contains('$')) {
+        return None;
+    }
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\'' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Some(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("environment.d").join(DISTROD_DROP_IN_NAME);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let cont = "# Added by hand\nEDITOR=vim\n\nLANG=C.UTF-8\n";
+        std::fs::write(&path, cont).unwrap();
+
+        let mut drop_in = EnvironmentDDropIn::open(&path).unwrap();
+        assert_eq!(cont, &drop_in.serialize());
+        assert_eq!(Some("vim"), drop_in.get_env("EDITOR"));
+
+        drop_in.put_env("LANG".to_owned(), "en_US.UTF-8".to_owned());
+        drop_in.put_env("WSL_DISTRO".to_owned(), "value with space".to_owned());
+        drop_in.write().unwrap();
+
+        let drop_in = EnvironmentDDropIn::open(&path).unwrap();
+        assert_eq!(
+            "# Added by hand\nEDITOR=vim\n\nLANG=en_US.UTF-8\nWSL_DISTRO=value with space\n",
+            &drop_in.serialize()
+        );
+    }
+
+    #[test]
+    fn test_put_path() {
+        let mut drop_in = EnvironmentDDropIn::parse(PathBuf::from(DISTROD_DROP_IN_NAME), "");
+        drop_in.put_path("/opt/distrod/bin".to_owned(), true);
+        assert_eq!("PATH=/opt/distrod/bin:$PATH\n", &drop_in.serialize());
+        drop_in.put_path("/mnt/c/Windows".to_owned(), false);
+        drop_in.put_path("/usr/local/distrod".to_owned(), true);
+        drop_in.put_path("/opt/distrod/bin".to_owned(), false);
+        assert_eq!(
+            "PATH=/usr/local/distrod:/opt/distrod/bin:$PATH:/mnt/c/Windows\n",
+            &drop_in.serialize()
+        );
+
+        let mut drop_in =
+            EnvironmentDDropIn::parse(PathBuf::from(DISTROD_DROP_IN_NAME), "PATH=/a:${PATH}\n");
+        drop_in.put_path("/b".to_owned(), false);
+        assert_eq!("PATH=/a:${PATH}:/b\n", &drop_in.serialize());
+
+        // A PATH that replaces the original value keeps doing so.
+        let mut drop_in =
+            EnvironmentDDropIn::parse(PathBuf::from(DISTROD_DROP_IN_NAME), "PATH=/a\n");
+        drop_in.put_path("/b".to_owned(), true);
+        assert_eq!("PATH=/b:/a\n", &drop_in.serialize());
+    }
+
+    #[test]
+    fn test_write_user_session_env() {
+        let home = tempfile::tempdir().unwrap();
+        let mut script = EnvShellScript::new();
+        script
+            .put_env("EDITOR".to_owned(), "vim".to_owned())
+            .unwrap();
+        script
+            .put_env_expr("GOPATH".to_owned(), "${HOME}/go".to_owned())
+            .unwrap();
+        script.put_env("PRICE".to_owned(), "$5".to_owned()).unwrap();
+        script.put_path("/opt/distrod/bin".to_owned(), true);
+        script.put_path("/mnt/c/Windows".to_owned(), false);
+
+        let options = UserSessionEnvOptions {
+            owner: Some((nix::unistd::getuid(), nix::unistd::getgid())),
+        };
+        let report = script
+            .write_user_session_env(home.path(), &options)
+            .unwrap();
+        let path = home
+            .path()
+            .join(".config/environment.d")
+            .join(USER_SESSION_DROP_IN_NAME);
+        assert_eq!(path, report.path);
+        assert_eq!(
+            "EDITOR=vim\nGOPATH=${HOME}/go\nPATH=/opt/distrod/bin:$PATH:/mnt/c/Windows\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(vec!["EDITOR", "GOPATH", "PATH"], report.changed_vars);
+        assert_eq!(vec!["PRICE"], report.skipped);
+        assert!(report
+            .dbus_update_script()
+            .contains("dbus-update-activation-environment --systemd EDITOR GOPATH PATH\n"));
+    }
+
+    #[test]
+    fn test_write_user_session_env_ignores_symlinks() {
+        let home = tempfile::tempdir().unwrap();
+        let path = home
+            .path()
+            .join(".config/environment.d")
+            .join(USER_SESSION_DROP_IN_NAME);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let victim = home.path().join("shadow");
+        std::fs::write(&victim, "root:secret\n").unwrap();
+        std::os::unix::fs::symlink(&victim, &path).unwrap();
+        let mut script = EnvShellScript::new();
+        script
+            .put_env("EDITOR".to_owned(), "vim".to_owned())
+            .unwrap();
+        let options = UserSessionEnvOptions {
+            owner: Some((nix::unistd::getuid(), nix::unistd::getgid())),
+        };
+        assert!(script
+            .write_user_session_env(home.path(), &options)
+            .is_err());
+        assert_eq!("root:secret\n", std::fs::read_to_string(&victim).unwrap());
+
+        // A symlink put after the drop-in is read is replaced, not written through.
+        std::fs::remove_file(&path).unwrap();
+        let mut drop_in = EnvironmentDDropIn::open(&path).unwrap();
+        drop_in.put_env("EDITOR".to_owned(), "vim".to_owned());
+        std::os::unix::fs::symlink(&victim, &path).unwrap();
+        drop_in.write().unwrap();
+        assert_eq!("root:secret\n", std::fs::read_to_string(&victim).unwrap());
+        assert!(std::fs::symlink_metadata(&path).unwrap().is_file());
+        assert_eq!("EDITOR=vim\n", std::fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn test_write_user_session_env_merges() {
+        let home = tempfile::tempdir().unwrap();
+        let path = home
+            .path()
+            .join(".config/environment.d")
+            .join(USER_SESSION_DROP_IN_NAME);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            "# Added by hand\nLANG=C.UTF-8\nEDITOR=nano\nOLD_VAR=1\nPATH=/opt/distrod/bin:$PATH\n",
+        )
+        .unwrap();
+
+        let mut script = EnvShellScript::new();
+        script
+            .put_env("EDITOR".to_owned(), "vim".to_owned())
+            .unwrap();
+        script.remove_env("OLD_VAR".to_owned());
+        script.put_path("/opt/distrod/bin".to_owned(), true);
+        let options = UserSessionEnvOptions::default();
+        let report = script
+            .write_user_session_env(home.path(), &options)
+            .unwrap();
+        assert_eq!(
+            "# Added by hand\nLANG=C.UTF-8\nEDITOR=vim\nPATH=/opt/distrod/bin:$PATH\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(vec!["EDITOR"], report.changed_vars);
+
+        let report = script
+            .write_user_session_env(home.path(), &options)
+            .unwrap();
+        assert!(report.changed_vars.is_empty());
+        assert_eq!("", report.dbus_update_script());
+    }
+
+    #[test]
+    fn test_env_modifier() {
+        let mut drop_in = EnvironmentDDropIn::parse(PathBuf::from(DISTROD_DROP_IN_NAME), "");
+        let modifier: &mut dyn EnvModifier = &mut drop_in;
+        modifier.put_env("DISPLAY", ":0").unwrap();
+        modifier.put_path("/opt/distrod/bin", true).unwrap();
+        assert!(modifier.put_env("FOO BAR", "foo").is_err());
+        assert!(modifier.put_path("/a:/b", false).is_err());
+        // systemd would expand `$5` whether it's escaped or not.
+        assert!(modifier.put_env("PRICE", "$5").is_err());
+        assert!(modifier.put_path("/opt/$USER/bin", true).is_err());
+        modifier.put_env("QUOTED", "'a b' \\ \"c\"").unwrap();
+        assert_eq!(
+            "DISPLAY=:0\nPATH=/opt/distrod/bin:$PATH\nQUOTED=\\'a b\\' \\\\ \\\"c\\\"\n",
+            &drop_in.serialize()
+        );
+    }
+}
@@ -3,6 +3,7 @@ pub mod container_org_image;
 pub mod distro_image;
 pub mod distrod_config;
 pub mod local_image;
+pub mod shell_quote;
 
 #[cfg(target_os = "linux")]
 pub mod command_alias;
@@ -11,8 +12,28 @@ pub mod container;
 #[cfg(target_os = "linux")]
 pub mod distro;
 #[cfg(target_os = "linux")]
+pub mod dotenv_file;
+#[cfg(all(target_os = "linux", feature = "env-config"))]
+pub mod env_config_file;
+#[cfg(target_os = "linux")]
+pub mod env_doctor;
+#[cfg(target_os = "linux")]
+pub mod env_file_set;
+#[cfg(target_os = "linux")]
+pub mod env_report;
+#[cfg(target_os = "linux")]
+pub mod env_schema;
+#[cfg(target_os = "linux")]
+pub mod env_sync;
+#[cfg(target_os = "linux")]
 pub mod envfile;
 #[cfg(target_os = "linux")]
+pub mod environment_d;
+#[cfg(target_os = "linux")]
+pub mod locale_file;
+#[cfg(target_os = "linux")]
+pub mod login_defs;
+#[cfg(target_os = "linux")]
 pub mod mount_info;
 #[cfg(target_os = "linux")]
 pub mod multifork;
@@ -21,9 +42,17 @@ pub mod passwd;
 #[cfg(target_os = "linux")]
 pub mod procfile;
 #[cfg(target_os = "linux")]
+pub mod shared_env_file;
+#[cfg(target_os = "linux")]
+pub mod systemd_env;
+#[cfg(target_os = "linux")]
 pub mod systemdunit;
 #[cfg(target_os = "linux")]
+pub mod windows_env;
+#[cfg(target_os = "linux")]
 pub mod wsl_interop;
+#[cfg(target_os = "linux")]
+pub mod wslenv;
 
 #[cfg(target_os = "linux")]
 pub mod template;
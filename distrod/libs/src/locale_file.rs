@@ -0,0 +1,259 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::envfile::{EnvFile, QuoteStyle};
+
+/// Where Debian and Ubuntu keep the system locale, read by pam_env.so like /etc/environment.
+pub const DEFAULT_LOCALE_FILE_PATH: &str = "/etc/default/locale";
+
+/// The categories of locale(7) that can be set separately from LANG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LcCategory {
+    All,
+    Address,
+    Collate,
+    Ctype,
+    Identification,
+    Measurement,
+    Messages,
+    Monetary,
+    Name,
+    Numeric,
+    Paper,
+    Telephone,
+    Time,
+}
+
+impl LcCategory {
+    /// The name of the variable, e.g. "LC_MESSAGES".
+    pub fn key(self) -> &'static str {
+        match self {
+            LcCategory::All => "LC_ALL",
+            LcCategory::Address => "LC_ADDRESS",
+            LcCategory::Collate => "LC_COLLATE",
+            LcCategory::Ctype => "LC_CTYPE",
+            LcCategory::Identification => "LC_IDENTIFICATION",
+            LcCategory::Measurement => "LC_MEASUREMENT",
+            LcCategory::Messages => "LC_MESSAGES",
+            LcCategory::Monetary => "LC_MONETARY",
+            LcCategory::Name => "LC_NAME",
+            LcCategory::Numeric => "LC_NUMERIC",
+            LcCategory::Paper => "LC_PAPER",
+            LcCategory::Telephone => "LC_TELEPHONE",
+            LcCategory::Time => "LC_TIME",
+        }
+    }
+}
+
+/// LocaleFile edits /etc/default/locale, whose syntax is the same as /etc/environment.
+/// New variables are quoted in the way the variables already in the file are, and in double
+/// quotes as update-locale of Debian does if the file has none.
+#[derive(Debug, Clone)]
+pub struct LocaleFile {
+    pub env_file: EnvFile,
+}
+
+impl LocaleFile {
+    /// A missing file is fine; it's created when it's written.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<LocaleFile> {
+        Ok(LocaleFile {
+            env_file: EnvFile::open(path)?,
+        })
+    }
+
+    pub fn lang(&self) -> Option<String> {
+        self.env_file.get_env("LANG")
+    }
+
+    pub fn get_lc(&self, category: LcCategory) -> Option<String> {
+        self.env_file.get_env(category.key())
+    }
+
+    pub fn set_lang(&mut self, lang: &str) -> Result<()> {
+        self.put_locale("LANG", lang)
+    }
+
+    pub fn set_lc(&mut self, category: LcCategory, value: &str) -> Result<()> {
+        self.put_locale(category.key(), value)
+    }
+
+    /// Set LANG to `fallback` and write the file, but only if neither this file nor
+    /// `environment`, the /etc/environment of the distro, has LANG. Returns Ok(true) if the
+    /// file is written.
+    pub fn ensure_default(&mut self, fallback: &str, environment: &EnvFile) -> Result<bool> {
+        if self.lang().is_some() || environment.get_env("LANG").is_some() {
+            return Ok(false);
+        }
+        self.set_lang(fallback)?;
+        self.write()?;
+        Ok(true)
+    }
+
+    pub fn write(&mut self) -> Result<()> {
+        self.env_file.write()
+    }
+
+    fn put_locale(&mut self, key: &str, locale: &str) -> Result<()> {
+        if !is_valid_locale(locale) {
+            bail!("{:?} is not a valid locale for {}.", locale, key);
+        }
+        let style = self.quote_style_for(key);
+        self.env_file
+            .put_env_with_style(key.to_owned(), locale.to_owned(), style)
+    }
+
+    fn quote_style_for(&self, key: &str) -> QuoteStyle {
+        if self.env_file.get_env_raw(key).is_some() {
+            return QuoteStyle::PreserveExisting;
+        }
+        let first_value = self
+            .env_file
+            .keys()
+            .into_iter()
+            .find_map(|key| self.env_file.get_env_raw(key));
+        match first_value {
+            Some(value) if value.starts_with('\'') => QuoteStyle::Single,
+            Some(value) if !value.starts_with('"') => QuoteStyle::None,
+            _ => QuoteStyle::Double,
+        }
+    }
+}
+
+/// Returns true for `C` and `POSIX`, and for names like `en_US.UTF-8`, that is, a language
+/// code, optionally followed by `_TERRITORY`, `.codeset` and `@modifier` in this order.
+/// `C.UTF-8` is accepted as well.
+pub fn is_valid_locale(locale: &str) -> bool {
+    let (locale, modifier) = match locale.split_once('@') {
+        Some((locale, modifier)) => (locale, Some(modifier)),
+        None => (locale, None),
+    };
+    let (name, codeset) = match locale.split_once('.') {
+        Some((name, codeset)) => (name, Some(codeset)),
+        None => (locale, None),
+    };
+    if let Some(codeset) = codeset {
+        if codeset.is_empty()
+            || !codeset
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return false;
+        }
+    }
+    if let Some(modifier) = modifier {
+        if modifier.is_empty() || !modifier.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return false;
+        }
+    }
+    if name == "C" || name == "POSIX" {
+        return modifier.is_none();
+    }
+    let (language, territory) = match name.split_once('_') {
+        Some((language, territory)) => (language, Some(territory)),
+        None => (name, None),
+    };
+    let is_valid_language =
+        (2..=3).contains(&language.len()) && language.chars().all(|c| c.is_ascii_lowercase());
+    // A territory is a country code like US, or a region code of UN M.49 like 419.
+    let is_valid_territory = territory.is_none_or(|territory| {
+        (territory.len() == 2 && territory.chars().all(|c| c.is_ascii_uppercase()))
+            || (territory.len() == 3 && territory.chars().all(|c| c.is_ascii_digit()))
+    });
+    is_valid_language && is_valid_territory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debian_style_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locale");
+        std::fs::write(
+            &path,
+            "#  File generated by update-locale\nLANG=\"en_US.UTF-8\"\n",
+        )
+        .unwrap();
+
+        let mut locale_file = LocaleFile::open(&path).unwrap();
+        assert_eq!(Some("en_US.UTF-8"), locale_file.lang().as_deref());
+        locale_file.set_lang("ja_JP.UTF-8").unwrap();
+        locale_file.set_lc(LcCategory::Messages, "C").unwrap();
+        assert!(locale_file
+            .set_lc(LcCategory::Time, "en_US.UTF-8; rm")
+            .is_err());
+        assert!(locale_file.set_lang("$(reboot)").is_err());
+        locale_file.write().unwrap();
+        assert_eq!(
+            "#  File generated by update-locale\nLANG=\"ja_JP.UTF-8\"\nLC_MESSAGES=\"C\"\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+
+        // The unquoted style of the file is followed as well.
+        std::fs::write(&path, "LANG=C.UTF-8\n").unwrap();
+        let mut locale_file = LocaleFile::open(&path).unwrap();
+        locale_file
+            .set_lc(LcCategory::Collate, "de_DE.UTF-8")
+            .unwrap();
+        locale_file.write().unwrap();
+        assert_eq!(
+            "LANG=C.UTF-8\nLC_COLLATE=de_DE.UTF-8\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+
+        let environment = EnvFile::open(dir.path().join("environment")).unwrap();
+        assert!(!locale_file.ensure_default("C.UTF-8", &environment).unwrap());
+    }
+
+    #[test]
+    fn test_ensure_default_on_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locale");
+        let environment_path = dir.path().join("environment");
+
+        std::fs::write(&environment_path, "LANG=fr_FR.UTF-8\n").unwrap();
+        let environment = EnvFile::open(&environment_path).unwrap();
+        let mut locale_file = LocaleFile::open(&path).unwrap();
+        assert!(!locale_file.ensure_default("C.UTF-8", &environment).unwrap());
+        assert!(!path.exists());
+
+        std::fs::write(&environment_path, "PATH=/usr/bin:/bin\n").unwrap();
+        let environment = EnvFile::open(&environment_path).unwrap();
+        assert!(locale_file.ensure_default("C.UTF-8", &environment).unwrap());
+        assert_eq!(
+            "LANG=\"C.UTF-8\"\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_is_valid_locale() {
+        for locale in &[
+            "C",
+            "POSIX",
+            "C.UTF-8",
+            "en_US.UTF-8",
+            "en_US.utf8",
+            "es_419.UTF-8",
+            "de_DE@euro",
+            "ast_ES.UTF-8",
+            "ja",
+        ] {
+            assert!(is_valid_locale(locale), "{}", locale);
+        }
+        for locale in &[
+            "",
+            "en_us.UTF-8",
+            "EN_US",
+            "en_US.",
+            "en_US.UTF 8",
+            "C@euro",
+            "en-US",
+            "en_US.UTF-8\"",
+        ] {
+            assert!(!is_valid_locale(locale), "{}", locale);
+        }
+    }
+}
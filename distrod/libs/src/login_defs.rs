@@ -0,0 +1,391 @@
+use std::{
+    io::{BufWriter, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::envfile::{
+    create_tmp_file, finish_writing, sync_parent_dir, Durability, NormalizeMode, PathVariable,
+};
+
+pub const DEFAULT_LOGIN_DEFS_PATH: &str = "/etc/login.defs";
+
+/// What login(1) of shadow uses if login.defs doesn't have ENV_PATH or ENV_SUPATH.
+const DEFAULT_ENV_PATH: &str = "PATH=/bin:/usr/bin";
+const DEFAULT_ENV_SUPATH: &str = "PATH=/sbin:/bin:/usr/sbin:/usr/bin";
+
+/// LoginDefs edits /etc/login.defs, from which login(1) takes PATH of console logins with
+/// ENV_PATH and ENV_SUPATH instead of /etc/environment. Each definition is a key and a value
+/// separated by whitespace, such as `ENV_PATH PATH=/usr/bin:/bin`. The lines that are not
+/// changed are written back byte for byte.
+#[derive(Debug, Clone)]
+pub struct LoginDefs {
+    pub file_path: PathBuf,
+    // Each line with its line ending, if any.
+    lines: Vec<String>,
+    dirty: bool,
+}
+
+/// Where the key and the value are in a line.
+struct Definition<'a> {
+    key: &'a str,
+    // The byte range of the value in the line, without the whitespace around it.
+    value: std::ops::Range<usize>,
+}
+
+impl LoginDefs {
+    /// A missing file is fine; it's created when it's written.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<LoginDefs> {
+        let path = path.as_ref();
+        let cont = match std::fs::read_to_string(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            cont => cont.with_context(|| format!("Failed to read {:?}", path))?,
+        };
+        Ok(LoginDefs {
+            file_path: path.to_owned(),
+            lines: cont.split_inclusive('\n').map(str::to_owned).collect(),
+            dirty: false,
+        })
+    }
+
+    /// Returns the value of the last definition of `key`, which is the one login(1) takes,
+    /// without the double quotes around it if any.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let (index, definition) = self.find(key)?;
+        Some(unquote(&self.lines[index][definition.value]))
+    }
+
+    /// Set `key` to `value`. The last definition of `key` gets the value, keeping the
+    /// whitespace and the quotes around it. If `key` is not defined but there is a commented
+    /// out default like `#ENV_PATH PATH=/bin`, the line is uncommented instead, so that the
+    /// definition stays where the distro put it. Otherwise a line is added to the end.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        if key.is_empty() || key.starts_with('#') || key.contains(char::is_whitespace) {
+            bail!("{:?} is not a valid key of {:?}.", key, &self.file_path);
+        }
+        if value.contains(&['\n', '\r'][..]) || value.trim() != value {
+            bail!("{}={:?} can't be put to {:?}.", key, value, &self.file_path);
+        }
+        if let Some((index, definition)) = self.find(key) {
+            let line = &self.lines[index];
+            let range = unquoted_range(line, definition.value);
+            if line[range.clone()] == *value {
+                return Ok(());
+            }
+            let new_line = format!("{}{}{}", &line[..range.start], value, &line[range.end..]);
+            self.lines[index] = new_line;
+        } else if let Some((index, body_start)) = self.find_disabled(key) {
+            let line = &self.lines[index];
+            let definition = parse_definition(&line[body_start..]).expect("parsed before");
+            let value_start = body_start + definition.value.start;
+            let value_end = body_start + definition.value.end;
+            let new_line = format!(
+                "{}{}{}",
+                &line[body_start..value_start],
+                value,
+                &line[value_end..]
+            );
+            self.lines[index] = new_line;
+        } else {
+            if let Some(last) = self.lines.last_mut() {
+                if !last.ends_with('\n') {
+                    last.push('\n');
+                }
+            }
+            let separator = self.separator();
+            self.lines.push(format!("{}{}{}\n", key, separator, value));
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Put `element` to the `PATH=` value of ENV_PATH, which is the PATH of console logins of
+    /// the users other than root. If ENV_PATH is not defined, the commented out default or
+    /// the default of login(1) is extended. Returns false if it's already there.
+    pub fn extend_env_path(&mut self, element: &str, prepend: bool) -> Result<bool> {
+        self.extend_path_of("ENV_PATH", DEFAULT_ENV_PATH, element, prepend)
+    }
+
+    /// Like extend_env_path, but for ENV_SUPATH, the PATH of root.
+    pub fn extend_env_supath(&mut self, element: &str, prepend: bool) -> Result<bool> {
+        self.extend_path_of("ENV_SUPATH", DEFAULT_ENV_SUPATH, element, prepend)
+    }
+
+    fn extend_path_of(
+        &mut self,
+        key: &str,
+        default: &str,
+        element: &str,
+        prepend: bool,
+    ) -> Result<bool> {
+        // login.defs has no quoting, so such an element would break the value.
+        let is_special = |c: char| c.is_whitespace() || ":\"#".contains(c);
+        if element.is_empty() || element.contains(is_special) {
+            bail!(
+                "{:?} can't be put to {} of {:?}.",
+                element,
+                key,
+                &self.file_path
+            );
+        }
+        let defined = self.get(key).map(str::to_owned);
+        let is_defined = defined.is_some();
+        let current = match defined {
+            Some(value) => value,
+            None => self
+                .find_disabled(key)
+                .map(|(index, body_start)| {
+                    let body = &self.lines[index][body_start..];
+                    let definition = parse_definition(body).expect("parsed before");
+                    unquote(&body[definition.value]).to_owned()
+                })
+                .unwrap_or_else(|| default.to_owned()),
+        };
+        // login(1) takes a value without `PATH=` as the directories as well.
+        let (prefix, paths) = match current.strip_prefix("PATH=") {
+            Some(paths) => ("PATH=", paths),
+            None => ("", current.as_str()),
+        };
+        let mut path_variable = PathVariable::parse(paths);
+        path_variable.normalize_mode(NormalizeMode::TrailingSlash);
+        if !path_variable.put_path_with_position(element, prepend) && is_defined {
+            return Ok(false);
+        }
+        // The elements are joined as they are, while serialize() would quote the new one for
+        // the shell.
+        let paths: Vec<_> = path_variable.iter().collect();
+        self.set(key, &format!("{}{}", prefix, paths.join(":")))?;
+        Ok(true)
+    }
+
+    pub fn serialize(&self) -> String {
+        self.lines.concat()
+    }
+
+    /// Write the file if anything has been changed, keeping its mode. The file is replaced by
+    /// renaming a complete copy over it, so that a login never reads a partial file.
+    pub fn write(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mode = match std::fs::metadata(&self.file_path) {
+            Ok(metadata) => metadata.permissions().mode() & 0o7777,
+            Err(_) => 0o644,
+        };
+        let (file, tmp_path) = create_tmp_file(&self.file_path)?;
+        let result = file
+            .set_permissions(std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set the mode of {:?}.", &tmp_path))
+            .and_then(|_| {
+                let mut file = BufWriter::new(file);
+                file.write_all(self.serialize().as_bytes())
+                    .with_context(|| format!("Failed to write {:?}.", &tmp_path))?;
+                finish_writing(file, &tmp_path, Durability::default())
+            })
+            .and_then(|_| {
+                std::fs::rename(&tmp_path, &self.file_path).with_context(|| {
+                    format!("Failed to rename {:?} to {:?}.", &tmp_path, &self.file_path)
+                })
+            });
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        sync_parent_dir(&self.file_path, Durability::default())?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn find(&self, key: &str) -> Option<(usize, Definition<'_>)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(i, line)| Some((i, parse_definition(line)?)))
+            .find(|(_, definition)| definition.key == key)
+    }
+
+    /// Returns the index of the first commented out definition of `key` and where it starts
+    /// after `#`. Only a comment of a key and a single word is taken as a definition, so that
+    /// a sentence mentioning the key, such as `# ENV_PATH is required`, isn't uncommented.
+    fn find_disabled(&self, key: &str) -> Option<(usize, usize)> {
+        self.lines.iter().enumerate().find_map(|(i, line)| {
+            let rest = line.trim_start().strip_prefix('#')?;
+            let body = rest.trim_start_matches(&[' ', '\t'][..]);
+            let definition = parse_definition(body)?;
+            let value = &body[definition.value.clone()];
+            if definition.key != key || value.contains(char::is_whitespace) {
+                return None;
+            }
+            Some((i, line.len() - body.len()))
+        })
+    }
+
+    /// The whitespace between the key and the value of the first definition, so that a new
+    /// line is aligned in the same way as the others, or a tab if there is none.
+    fn separator(&self) -> String {
+        self.lines
+            .iter()
+            .find_map(|line| {
+                let definition = parse_definition(line)?;
+                let key_end = line.find(definition.key)? + definition.key.len();
+                Some(line[key_end..definition.value.start].to_owned())
+            })
+            .unwrap_or_else(|| "\t".to_owned())
+    }
+}
+
+/// Parse a line of `KEY value`. Returns None for a comment, a blank line or a key without a
+/// value.
+fn parse_definition(line: &str) -> Option<Definition<'_>> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('#') {
+        return None;
+    }
+    let key_start = line.len() - trimmed.len();
+    let key_len = trimmed.find(char::is_whitespace)?;
+    let after_key = &trimmed[key_len..];
+    let value = after_key.trim();
+    if value.is_empty() {
+        return None;
+    }
+    let value_start = key_start + key_len + (after_key.len() - after_key.trim_start().len());
+    Some(Definition {
+        key: &trimmed[..key_len],
+        value: value_start..value_start + value.len(),
+    })
+}
+
+fn unquote(value: &str) -> &str {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(unquoted) => unquoted,
+        None => value,
+    }
+}
+
+/// The range of `value` in `line` without the double quotes around it if any.
+fn unquoted_range(line: &str, value: std::ops::Range<usize>) -> std::ops::Range<usize> {
+    let quoted = &line[value.clone()];
+    if quoted.len() >= 2 && quoted.starts_with('"') && quoted.ends_with('"') {
+        return value.start + 1..value.end - 1;
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/resources/login_defs/login.defs");
+
+    fn open_fixture(dir: &Path) -> LoginDefs {
+        let path = dir.join("login.defs");
+        std::fs::write(&path, FIXTURE).unwrap();
+        LoginDefs::open(&path).unwrap()
+    }
+
+    /// The lines of `after` that differ from the ones of `before` at the same positions.
+    fn changed_lines<'a>(before: &str, after: &'a str) -> Vec<&'a str> {
+        let before: Vec<_> = before.split_inclusive('\n').collect();
+        after
+            .split_inclusive('\n')
+            .enumerate()
+            .filter(|(i, line)| before.get(*i) != Some(line))
+            .map(|(_, line)| line)
+            .collect()
+    }
+
+    #[test]
+    fn test_get_and_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut login_defs = open_fixture(dir.path());
+        assert_eq!(Some("/var/mail"), login_defs.get("MAIL_DIR"));
+        assert_eq!(Some("022"), login_defs.get("UMASK"));
+        assert_eq!(None, login_defs.get("ENV_PATH"));
+        assert_eq!(None, login_defs.get("MAIL_FILE"));
+        assert_eq!(None, login_defs.get("Three"));
+
+        login_defs.set("UMASK", "027").unwrap();
+        login_defs.set("MAIL_FILE", ".mailbox").unwrap();
+        login_defs.set("HOME_MODE", "0700").unwrap();
+        assert!(login_defs.set("BAD KEY", "1").is_err());
+        assert!(login_defs.set("FAIL_DELAY", "3\nUMASK 000").is_err());
+        login_defs.write().unwrap();
+
+        let written = std::fs::read_to_string(&login_defs.file_path).unwrap();
+        assert_eq!(
+            vec![
+                "MAIL_FILE      .mailbox\n",
+                "UMASK\t\t027\n",
+                // Aligned as the first definition in the file is.
+                "HOME_MODE\t\t0700\n"
+            ],
+            changed_lines(FIXTURE, &written)
+        );
+        assert_eq!(Some(".mailbox"), login_defs.get("MAIL_FILE"));
+    }
+
+    #[test]
+    fn test_extend_env_path_uncomments_the_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut login_defs = open_fixture(dir.path());
+        assert!(login_defs
+            .extend_env_path("/opt/distrod/bin", true)
+            .unwrap());
+        assert!(login_defs
+            .extend_env_supath("/opt/distrod/bin", false)
+            .unwrap());
+        assert!(!login_defs
+            .extend_env_path("/opt/distrod/bin/", true)
+            .unwrap());
+        assert!(login_defs.extend_env_path("/opt/my bin", true).is_err());
+        assert!(login_defs.extend_env_path("/a:/b", true).is_err());
+        login_defs.write().unwrap();
+
+        let written = std::fs::read_to_string(&login_defs.file_path).unwrap();
+        assert_eq!(
+            vec![
+                "ENV_SUPATH\tPATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin:/opt/distrod/bin\n",
+                "ENV_PATH\tPATH=/opt/distrod/bin:/usr/local/bin:/usr/bin:/bin:/usr/local/games:/usr/games\n",
+            ],
+            changed_lines(FIXTURE, &written)
+        );
+        assert_eq!(
+            FIXTURE.len() + 2 * "/opt/distrod/bin:".len() - 1,
+            written.len()
+        );
+    }
+
+    #[test]
+    fn test_extend_env_path_without_definitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("login.defs");
+        std::fs::write(&path, "# ENV_PATH is not set here\nUMASK 022").unwrap();
+        let mut login_defs = LoginDefs::open(&path).unwrap();
+        assert!(login_defs
+            .extend_env_path("/opt/distrod/bin", false)
+            .unwrap());
+        login_defs.write().unwrap();
+        assert_eq!(
+            "# ENV_PATH is not set here\nUMASK 022\nENV_PATH PATH=/bin:/usr/bin:/opt/distrod/bin\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+
+        // A value without `PATH=` and in quotes is extended as it is.
+        std::fs::write(&path, "ENV_PATH \"/usr/bin:/bin\"\n").unwrap();
+        let mut login_defs = LoginDefs::open(&path).unwrap();
+        assert!(login_defs
+            .extend_env_path("/opt/distrod/bin", true)
+            .unwrap());
+        assert_eq!(
+            "ENV_PATH \"/opt/distrod/bin:/usr/bin:/bin\"\n",
+            login_defs.serialize()
+        );
+        assert_eq!(
+            Some("/opt/distrod/bin:/usr/bin:/bin"),
+            login_defs.get("ENV_PATH")
+        );
+    }
+}
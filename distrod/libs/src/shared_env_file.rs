@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use anyhow::{bail, Result};
+
+use crate::envfile::{EnvFile, EnvFileOptions};
+
+/// An EnvFile shared by threads, such as the ones of the daemon serving its clients, which
+/// all see the same variables instead of diverging clones. Clones of SharedEnvFile share the
+/// EnvFile. Changes are made under the lock, and write() also takes the flock on the file,
+/// so that two SharedEnvFiles, even in different processes, don't clobber each other.
+#[derive(Debug, Clone)]
+pub struct SharedEnvFile {
+    env_file: Arc<RwLock<EnvFile>>,
+}
+
+impl SharedEnvFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SharedEnvFile> {
+        Ok(SharedEnvFile::from(EnvFile::open(path)?))
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: &EnvFileOptions,
+    ) -> Result<SharedEnvFile> {
+        Ok(SharedEnvFile::from(EnvFile::open_with_options(
+            path, options,
+        )?))
+    }
+
+    /// See EnvFile::get_env.
+    pub fn get_env(&self, key: &str) -> Option<String> {
+        self.read_lock().get_env(key)
+    }
+
+    /// The keys in the order of their effective definitions.
+    pub fn keys(&self) -> Vec<String> {
+        self.read_lock()
+            .keys()
+            .into_iter()
+            .map(|key| key.to_owned())
+            .collect()
+    }
+
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.read_lock().to_map()
+    }
+
+    /// Call `f` with the EnvFile for what the other methods don't cover. Changes can't be
+    /// made while `f` runs.
+    pub fn read<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&EnvFile) -> T,
+    {
+        f(&self.read_lock())
+    }
+
+    /// Make changes to the EnvFile, which the other threads see all at once. If `change`
+    /// fails, none of the changes it made are kept.
+    pub fn update<F, T>(&self, change: F) -> Result<T>
+    where
+        F: FnOnce(&mut EnvFile) -> Result<T>,
+    {
+        let mut env_file = self.write_lock();
+        let mut updated = env_file.clone();
+        let result = change(&mut updated)?;
+        *env_file = updated;
+        Ok(result)
+    }
+
+    pub fn put_env(&self, key: String, value: String) -> Result<()> {
+        self.update(|env_file| env_file.put_env(key, value))
+    }
+
+    /// See EnvFile::put_path.
+    pub fn put_path(&self, path_val: String) -> Result<bool> {
+        self.update(|env_file| env_file.put_path(path_val))
+    }
+
+    pub fn remove_env(&self, key: &str) -> bool {
+        let mut env_file = self.write_lock();
+        env_file.remove_env(key)
+    }
+
+    /// Write the changes to the file while holding the flock on it, merging them with what
+    /// others have written since it was read as EnvFile::write_merged does.
+    pub fn write(&self) -> Result<()> {
+        let mut env_file = self.write_lock();
+        if !env_file.is_dirty() {
+            return Ok(());
+        }
+        let _lock = env_file.lock_exclusive()?;
+        env_file.write_merged()
+    }
+
+    /// Read the file again if its inode, size or mtime has changed since it was read or last
+    /// written. Returns whether it was read again. Fails if there are changes that have not
+    /// been written yet, which reading it again would lose.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        if !self.read_lock().is_stamp_changed() {
+            return Ok(false);
+        }
+        let mut env_file = self.write_lock();
+        // Someone else may have reloaded it while the lock was released.
+        if !env_file.is_stamp_changed() {
+            return Ok(false);
+        }
+        if env_file.is_dirty() {
+            bail!(
+                "{:?} has been modified by others, but it can't be read again since it has changes that have not been written.",
+                &env_file.file_path
+            );
+        }
+        env_file.reload()?;
+        Ok(true)
+    }
+
+    // The EnvFile is replaced only after a change has succeeded, so it's consistent even if
+    // a thread panicked while holding the lock.
+    fn read_lock(&self) -> RwLockReadGuard<'_, EnvFile> {
+        self.env_file.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, EnvFile> {
+        self.env_file
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl From<EnvFile> for SharedEnvFile {
+    fn from(env_file: EnvFile) -> Self {
+        SharedEnvFile {
+            env_file: Arc::new(RwLock::new(env_file)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readers_during_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("environment");
+        std::fs::write(&path, "FIRST=0\nSECOND=0\n").unwrap();
+        let shared = SharedEnvFile::open(&path).unwrap();
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        // The writer changes both at once.
+                        let map = shared.to_map();
+                        assert_eq!(map["FIRST"], map["SECOND"]);
+                    }
+                })
+            })
+            .collect();
+        for i in 1..=50 {
+            shared
+                .update(|env_file| {
+                    env_file.put_env("FIRST".to_owned(), i.to_string())?;
+                    env_file.put_env("SECOND".to_owned(), i.to_string())
+                })
+                .unwrap();
+            shared.write().unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(
+            "FIRST='50'\nSECOND='50'\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert!(shared
+            .update(|env_file| {
+                env_file.put_env("FIRST".to_owned(), "1".to_owned())?;
+                env_file.put_env("SECOND".to_owned(), "nul\0".to_owned())
+            })
+            .is_err());
+        assert_eq!(Some("50"), shared.get_env("FIRST").as_deref());
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("environment");
+        std::fs::write(&path, "LANG=C\n").unwrap();
+        let shared = SharedEnvFile::open(&path).unwrap();
+        assert!(!shared.reload_if_changed().unwrap());
+
+        let mut other = EnvFile::open(&path).unwrap();
+        other
+            .put_env("EDITOR".to_owned(), "vim".to_owned())
+            .unwrap();
+        other.write().unwrap();
+        assert_eq!(None, shared.get_env("EDITOR"));
+        assert!(shared.reload_if_changed().unwrap());
+        assert_eq!(vec!["LANG", "EDITOR"], shared.keys());
+        assert!(!shared.reload_if_changed().unwrap());
+
+        std::fs::write(&path, "LANG=C.UTF-8\n").unwrap();
+        shared
+            .put_env("PAGER".to_owned(), "less".to_owned())
+            .unwrap();
+        assert!(shared.reload_if_changed().is_err());
+        assert_eq!(Some("less"), shared.get_env("PAGER").as_deref());
+    }
+
+    #[test]
+    fn test_writes_of_two_instances_are_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("environment");
+        std::fs::write(&path, "LANG=C\n").unwrap();
+        let first = SharedEnvFile::open(&path).unwrap();
+        let second = SharedEnvFile::open(&path).unwrap();
+        first
+            .put_env("EDITOR".to_owned(), "vim".to_owned())
+            .unwrap();
+        second
+            .put_env("PAGER".to_owned(), "less".to_owned())
+            .unwrap();
+        first.write().unwrap();
+        second.write().unwrap();
+        assert_eq!(
+            "LANG=C\nEDITOR='vim'\nPAGER='less'\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Quoting of strings for POSIX shells, so that a string can be embedded in a generated
+//! script or command line and be seen by the shell exactly as it is.
+
+use std::borrow::Cow;
+
+/// Quote `s` in single quotes, in which nothing but a single quote is special. A single quote
+/// is written as `'"'"'`.
+pub fn single_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\"'\"'"))
+}
+
+/// Quote `s` in double quotes, escaping `$`, `` ` ``, `"` and `\` with a backslash.
+pub fn double_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        if ['$', '`', '"', '\\'].contains(&c) {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns `s` as it is if the shell would take it as a single word as it is, and
+/// single_quote(s) otherwise.
+pub fn quote_minimal(s: &str) -> Cow<'_, str> {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(single_quote(s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    const CORPUS: &[&str] = &[
+        "",
+        "plain",
+        "/usr/local/bin:/usr/bin",
+        "with space",
+        "it's",
+        "''",
+        "\"double\"",
+        "$HOME ${PATH} $(id) `id`",
+        "back\\slash\\",
+        "glob * ? [a]",
+        "~user",
+        "line\nbreak",
+        "semi;colon & pipe | redirect > <",
+        "#not a comment",
+        "!bang",
+        "tab\there",
+        "日本語 ünïcödé",
+        "KEY=value",
+        "-n",
+    ];
+
+    fn printed_by_shell(quoted: &str) -> String {
+        let output = Command::new("sh")
+            .args(["-c", &format!("printf %s {}", quoted)])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", quoted);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_quotes_round_trip_through_shell() {
+        for s in CORPUS {
+            assert_eq!(*s, printed_by_shell(&single_quote(s)), "single_quote");
+            assert_eq!(*s, printed_by_shell(&double_quote(s)), "double_quote");
+            assert_eq!(*s, printed_by_shell(&quote_minimal(s)), "quote_minimal");
+        }
+    }
+
+    #[test]
+    fn test_quote_minimal() {
+        assert!(matches!(
+            quote_minimal("/usr/bin:/bin"),
+            Cow::Borrowed("/usr/bin:/bin")
+        ));
+        assert_eq!("''", quote_minimal(""));
+        assert_eq!("'a b'", quote_minimal("a b"));
+        assert_eq!("'~'", quote_minimal("~"));
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::envfile::{finish_writing, path_with_suffix, sync_parent_dir, validate_key, Durability};
+
+/// The name of the drop-in SystemdEnvDropIn writes in the drop-in directory of a unit.
+pub const DISTROD_SERVICE_DROP_IN_NAME: &str = "50-distrod-env.conf";
+
+/// SystemdEnvDropIn sets variables for a service distrod manages, such as ssh or cron started
+/// by systemd in the distro, which doesn't get /etc/environment unless it goes through PAM.
+/// The variables are written to a drop-in of the unit, either as Environment= lines or as an
+/// environment file that the drop-in refers to with EnvironmentFile=.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemdEnvDropIn {
+    envs: Vec<(String, String)>,
+}
+
+impl SystemdEnvDropIn {
+    /// A key given more than once keeps its first position and gets the last value.
+    pub fn from_env_pairs<I, K, V>(pairs: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let mut drop_in = SystemdEnvDropIn::default();
+        for (key, value) in pairs {
+            let (key, value) = (key.into(), value.into());
+            match drop_in.envs.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, v)) => *v = value,
+                None => drop_in.envs.push((key, value)),
+            }
+        }
+        drop_in
+    }
+
+    /// Write `<dropin_dir>/<unit_name>.d/50-distrod-env.conf` with an Environment= line for
+    /// each variable, and return its path. `unit_name` is a full unit name such as
+    /// `ssh.service`, and `dropin_dir` is usually /etc/systemd/system. The unit gets the
+    /// variables only after `systemctl daemon-reload` and a restart.
+    pub fn write(&self, unit_name: &str, dropin_dir: &Path) -> Result<PathBuf> {
+        let mut cont = String::from("[Service]\n");
+        for line in self.environment_lines()? {
+            cont.push_str(&line);
+            cont.push('\n');
+        }
+        write_drop_in(&drop_in_dir(unit_name, dropin_dir)?, &cont)
+    }
+
+    /// Like write, but writes the variables to `environment_file` as KEY=value lines instead,
+    /// and makes the drop-in refer to it with EnvironmentFile=. This keeps long or many values
+    /// out of the unit, and the file can be updated without touching the drop-in, though the
+    /// service still has to be restarted to read it again.
+    pub fn write_environment_file(
+        &self,
+        unit_name: &str,
+        dropin_dir: &Path,
+        environment_file: &Path,
+    ) -> Result<PathBuf> {
+        let dir = drop_in_dir(unit_name, dropin_dir)?;
+        let file_name = match environment_file.to_str() {
+            Some(name) if environment_file.is_absolute() => name,
+            _ => bail!(
+                "{:?} can't be given to EnvironmentFile= since it's not an absolute UTF-8 path.",
+                environment_file
+            ),
+        };
+        if file_name.contains(&['\n', '\\'][..]) {
+            bail!("{:?} can't be given to EnvironmentFile=.", environment_file);
+        }
+        let mut env_cont = String::new();
+        for (key, value) in &self.envs {
+            check_env(key, value)?;
+            env_cont.push_str(&format!("{}={}\n", key, quote_for_environment_file(value)));
+        }
+        if let Some(dir) = environment_file.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+        }
+        replace_file(environment_file, &env_cont)?;
+        // The path is subject to specifier expansion, but not the content of the file.
+        write_drop_in(
+            &dir,
+            &format!(
+                "[Service]\nEnvironmentFile={}\n",
+                file_name.replace('%', "%%")
+            ),
+        )
+    }
+
+    fn environment_lines(&self) -> Result<Vec<String>> {
+        self.envs
+            .iter()
+            .map(|(key, value)| {
+                check_env(key, value)?;
+                Ok(format!(
+                    "Environment=\"{}={}\"",
+                    key,
+                    escape_for_environment(value)
+                ))
+            })
+            .collect()
+    }
+}
+
+fn check_env(key: &str, value: &str) -> Result<()> {
+    if validate_key(key).is_err() {
+        bail!("{:?} is not a valid variable name for systemd.", key);
+    }
+    // A newline would end the line of the unit file, and systemd can't take NUL at all.
+    if value.contains(&['\n', '\r', '\0'][..]) {
+        bail!(
+            "The value of {} can't be written to a unit: {:?}",
+            key,
+            value
+        );
+    }
+    Ok(())
+}
+
+/// Escape `value` for an assignment in a double-quoted Environment= value. systemd resolves
+/// specifiers such as `%h` before unquoting, so `%` is doubled, and then takes C-style escapes
+/// in the quotes, so backslashes and double quotes are escaped with a backslash. `$` is not
+/// expanded in Environment= and is written as it is.
+fn escape_for_environment(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '%' => escaped.push_str("%%"),
+            '\\' | '"' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quote `value` for an environment file, which systemd reads like a shell assignment:
+/// whitespace around an unquoted value is stripped, and in double quotes a backslash escapes
+/// `"`, `\`, `` ` `` and `$`.
+fn quote_for_environment_file(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '`' | '$') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn drop_in_dir(unit_name: &str, dropin_dir: &Path) -> Result<PathBuf> {
+    if unit_name.is_empty()
+        || unit_name.starts_with('.')
+        || !unit_name.contains('.')
+        || unit_name.contains(&['/', '\n'][..])
+    {
+        bail!("{:?} is not a unit name such as ssh.service.", unit_name);
+    }
+    Ok(dropin_dir.join(format!("{}.d", unit_name)))
+}
+
+fn write_drop_in(dir: &Path, cont: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    let path = dir.join(DISTROD_SERVICE_DROP_IN_NAME);
+    replace_file(&path, cont)?;
+    Ok(path)
+}
+
+/// Write `cont` to a temporary file next to `path` and rename it over `path`, so that systemd
+/// never reads a partial file.
+fn replace_file(path: &Path, cont: &str) -> Result<()> {
+    let tmp_path = path_with_suffix(path, ".tmp");
+    let result = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {:?}.", &tmp_path))
+        .and_then(|file| {
+            let mut file = BufWriter::new(file);
+            file.write_all(cont.as_bytes())
+                .with_context(|| format!("Failed to write {:?}.", &tmp_path))?;
+            finish_writing(file, &tmp_path, Durability::default())
+        })
+        .and_then(|_| {
+            std::fs::rename(&tmp_path, path)
+                .with_context(|| format!("Failed to rename {:?} to {:?}.", &tmp_path, path))
+        });
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    sync_parent_dir(path, Durability::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_escapes_as_systemd_exec_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let drop_in = SystemdEnvDropIn::from_env_pairs(vec![
+            ("GREETING", "hello world"),
+            ("QUOTED", "say \"hi\""),
+            ("BACKSLASH", "C:\\Users"),
+            ("PERCENT", "100% %h"),
+            ("SINGLE", "it's"),
+            ("DOLLAR", "$HOME"),
+            ("GREETING", "hi there"),
+        ]);
+        let path = drop_in.write("ssh.service", dir.path()).unwrap();
+        assert_eq!(
+            dir.path().join("ssh.service.d").join("50-distrod-env.conf"),
+            path
+        );
+        assert_eq!(
+            "[Service]\n\
+             Environment=\"GREETING=hi there\"\n\
+             Environment=\"QUOTED=say \\\"hi\\\"\"\n\
+             Environment=\"BACKSLASH=C:\\\\Users\"\n\
+             Environment=\"PERCENT=100%% %%h\"\n\
+             Environment=\"SINGLE=it's\"\n\
+             Environment=\"DOLLAR=$HOME\"\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(
+            1,
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count()
+        );
+    }
+
+    #[test]
+    fn test_write_rejects_what_a_unit_cant_have() {
+        let dir = tempfile::tempdir().unwrap();
+        for pairs in &[[("MULTI", "a\nb")], [("1ST", "a")], [("A-B", "a")]] {
+            let drop_in = SystemdEnvDropIn::from_env_pairs(pairs.iter().cloned());
+            assert!(drop_in.write("cron.service", dir.path()).is_err());
+            assert!(drop_in
+                .write_environment_file("cron.service", dir.path(), &dir.path().join("env"))
+                .is_err());
+        }
+        let drop_in = SystemdEnvDropIn::from_env_pairs(vec![("LANG", "C")]);
+        for unit_name in &["", "cron", "../cron.service", ".service"] {
+            assert!(drop_in.write(unit_name, dir.path()).is_err());
+        }
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_write_environment_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join("distrod 100%").join("cron.env");
+        let drop_in = SystemdEnvDropIn::from_env_pairs(vec![
+            ("GREETING", " hello world "),
+            ("QUOTED", "say \"hi\" to `$USER` \\o/"),
+            ("PERCENT", "100%"),
+        ]);
+        let path = drop_in
+            .write_environment_file("cron.service", dir.path(), &env_path)
+            .unwrap();
+        assert_eq!(
+            format!(
+                "[Service]\nEnvironmentFile={}/distrod 100%%/cron.env\n",
+                dir.path().to_str().unwrap().replace('%', "%%")
+            ),
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(
+            "GREETING=\" hello world \"\n\
+             QUOTED=\"say \\\"hi\\\" to \\`\\$USER\\` \\\\o/\"\n\
+             PERCENT=\"100%\"\n",
+            std::fs::read_to_string(&env_path).unwrap()
+        );
+        assert!(drop_in
+            .write_environment_file("cron.service", dir.path(), Path::new("cron.env"))
+            .is_err());
+
+        // systemd parses environment files as a shell would, and so does sh.
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(". \"$1\" && printf '%s|%s|%s' \"$GREETING\" \"$QUOTED\" \"$PERCENT\"")
+            .arg("sh")
+            .arg(&env_path)
+            .output()
+            .unwrap();
+        assert_eq!(
+            " hello world |say \"hi\" to `$USER` \\o/|100%",
+            String::from_utf8(output.stdout).unwrap()
+        );
+    }
+}
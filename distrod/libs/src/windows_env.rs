@@ -0,0 +1,449 @@
+use anyhow::Result;
+
+use crate::envfile::{validate_key, EnvFile, EnvShellScript};
+
+/// Where WSL mounts the drives of Windows by default.
+pub const DEFAULT_MOUNT_ROOT: &str = "/mnt";
+
+/// Which variables of Windows are imported by EnvShellScript::import_windows_env, and how.
+/// Names are compared case-insensitively as Windows does.
+#[derive(Debug, Clone)]
+pub struct WindowsImportOptions {
+    /// Only these variables are imported if it's Some. PATH has to be in it to import PATH.
+    pub allowlist: Option<Vec<String>>,
+    pub denylist: Vec<String>,
+    /// The directory where the drives of Windows are mounted, such as /mnt for /mnt/c.
+    pub mount_root: String,
+    /// Whether the paths in PATH of Windows take priority over the ones of Linux.
+    pub prepends_paths: bool,
+}
+
+impl Default for WindowsImportOptions {
+    fn default() -> Self {
+        WindowsImportOptions {
+            allowlist: None,
+            denylist: vec![],
+            mount_root: DEFAULT_MOUNT_ROOT.to_owned(),
+            prepends_paths: false,
+        }
+    }
+}
+
+impl WindowsImportOptions {
+    fn allows(&self, name: &str) -> bool {
+        let matches = |names: &Vec<String>| names.iter().any(|n| n.eq_ignore_ascii_case(name));
+        self.allowlist.as_ref().is_none_or(matches) && !matches(&self.denylist)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WindowsImportReport {
+    /// The names of the imported variables. PATH is included if any of its paths is imported.
+    pub imported: Vec<String>,
+    /// What was skipped and why.
+    pub warnings: Vec<String>,
+}
+
+/// Parse an environment block of Windows, which is a list of `NAME=value\0` terminated by
+/// another `\0`. Entries such as `=C:=C:\` that keep the current directories of drives are
+/// skipped.
+pub fn parse_windows_env_block(block: &str) -> Vec<(String, String)> {
+    block
+        .split('\0')
+        .filter_map(|entry| {
+            let (name, value) = entry.split_once('=')?;
+            if name.is_empty() {
+                return None;
+            }
+            Some((name.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+impl EnvShellScript {
+    /// Import the variables of Windows in `vars`, such as the ones parse_windows_env_block
+    /// returns. Values that are absolute paths of Windows are translated to the paths under
+    /// `opts.mount_root`, and the others are put as they are. PATH is split at `;` and its
+    /// paths are put in their order.
+    pub fn import_windows_env<I>(
+        &mut self,
+        vars: I,
+        opts: &WindowsImportOptions,
+    ) -> Result<WindowsImportReport>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut report = WindowsImportReport::default();
+        for (name, value) in vars {
+            if !opts.allows(&name) {
+                continue;
+            }
+            if name.eq_ignore_ascii_case("PATH") {
+                let paths = translate_windows_path_list(&value, opts, &mut report);
+                if !paths.is_empty() {
+                    let paths: Vec<_> = paths.iter().map(String::as_str).collect();
+                    self.put_paths_in_batch(&paths, opts.prepends_paths);
+                    report.imported.push(name);
+                }
+                continue;
+            }
+            if validate_key(&name).is_err() {
+                report.warnings.push(format!(
+                    "{} is skipped since it's not a valid name in Linux.",
+                    name
+                ));
+                continue;
+            }
+            let value = match translate_windows_path(&value, &opts.mount_root) {
+                Translation::Translated(path) => path,
+                Translation::NotPath => value,
+                Translation::Unc => {
+                    report.warnings.push(format!(
+                        "{} is skipped since its value is a UNC path: {}",
+                        name, value
+                    ));
+                    continue;
+                }
+            };
+            self.put_env(name.clone(), value)?;
+            report.imported.push(name);
+        }
+        Ok(report)
+    }
+}
+
+impl EnvShellScript {
+    /// Translate `win_path` with windows_path_to_wsl and put it like put_path.
+    pub fn put_windows_path(&mut self, win_path: &str, prepend: bool) -> Result<()> {
+        let path = windows_path_to_wsl(win_path, DEFAULT_MOUNT_ROOT)?;
+        self.put_path(path, prepend);
+        Ok(())
+    }
+}
+
+impl EnvFile {
+    /// Translate `win_path` with windows_path_to_wsl and put it like put_path, which fails
+    /// with EnvFileError::InvalidValue if it has a quote, as `C:\Users\O'Brien` does.
+    pub fn put_windows_path(&mut self, win_path: &str) -> Result<bool> {
+        let path = windows_path_to_wsl(win_path, DEFAULT_MOUNT_ROOT)?;
+        self.put_path(path)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowsPathError {
+    /// The path can't be reached under the mount root, such as a UNC path or one with a
+    /// colon, which PATH of Linux can't have.
+    Unsupported { path: String, reason: &'static str },
+    /// The path is neither an absolute path of Windows nor one under the mount root.
+    NotAbsolute(String),
+}
+
+impl std::fmt::Display for WindowsPathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WindowsPathError::Unsupported { path, reason } => {
+                write!(f, "{} is not supported: {}", path, reason)
+            }
+            WindowsPathError::NotAbsolute(path) => {
+                write!(f, "{} is not an absolute path of Windows.", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WindowsPathError {}
+
+/// Translate an absolute path of Windows such as `C:\Program Files\` to the path in WSL,
+/// `/mnt/c/Program Files` for the mount root /mnt. A path already under the mount root is
+/// returned as it is except for redundant slashes, so translating twice is harmless.
+/// Fails with WindowsPathError, and never looks at the filesystem.
+pub fn windows_path_to_wsl(path: &str, mount_root: &str) -> Result<String> {
+    let translated = match translate_windows_path(path, mount_root) {
+        Translation::Translated(translated) => translated,
+        Translation::Unc => {
+            return Err(WindowsPathError::Unsupported {
+                path: path.to_owned(),
+                reason: "UNC paths can't be translated",
+            }
+            .into())
+        }
+        Translation::NotPath => match normalize_translated_path(path, mount_root) {
+            Some(translated) => translated,
+            None => return Err(WindowsPathError::NotAbsolute(path.to_owned()).into()),
+        },
+    };
+    if translated.contains(':') {
+        return Err(WindowsPathError::Unsupported {
+            path: path.to_owned(),
+            reason: "PATH can't have a colon",
+        }
+        .into());
+    }
+    Ok(translated)
+}
+
+/// Returns `path` without redundant slashes if it's under a drive in `mount_root`.
+fn normalize_translated_path(path: &str, mount_root: &str) -> Option<String> {
+    let mount_root = mount_root.trim_end_matches('/');
+    let rest = path.strip_prefix(mount_root)?.strip_prefix('/')?;
+    let mut components = rest.split('/').filter(|component| !component.is_empty());
+    let drive = components.next()?;
+    if drive.len() != 1 || !drive.chars().all(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    let mut normalized = format!("{}/{}", mount_root, drive);
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    Some(normalized)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Translation {
+    Translated(String),
+    NotPath,
+    Unc,
+}
+
+/// Translate an absolute path of Windows such as `C:\Users\me\` to `/mnt/c/Users/me`.
+/// The extended-length form `\\?\C:\Users\me` is accepted as well.
+fn translate_windows_path(path: &str, mount_root: &str) -> Translation {
+    let path = path.strip_prefix(r"\\?\").unwrap_or(path);
+    if path.starts_with(r"\\") || path.starts_with("//") || path.starts_with(r"UNC\") {
+        return Translation::Unc;
+    }
+    let mut chars = path.chars();
+    let drive = match (chars.next(), chars.next(), chars.next()) {
+        (Some(drive), Some(':'), None)
+        | (Some(drive), Some(':'), Some('\\'))
+        | (Some(drive), Some(':'), Some('/'))
+            if drive.is_ascii_alphabetic() =>
+        {
+            drive.to_ascii_lowercase()
+        }
+        _ => return Translation::NotPath,
+    };
+    let mut translated = format!("{}/{}", mount_root.trim_end_matches('/'), drive);
+    for component in path[2..].split(&['\\', '/'][..]) {
+        if !component.is_empty() {
+            translated.push('/');
+            translated.push_str(component);
+        }
+    }
+    Translation::Translated(translated)
+}
+
+/// Split PATH of Windows, where an element can be quoted to have `;` in it, and translate
+/// the elements.
+fn translate_windows_path_list(
+    value: &str,
+    opts: &WindowsImportOptions,
+    report: &mut WindowsImportReport,
+) -> Vec<String> {
+    let mut elems = vec![];
+    let mut elem = String::new();
+    let mut in_quotes = false;
+    for c in value.chars().chain(std::iter::once(';')) {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => elems.push(std::mem::take(&mut elem)),
+            c => elem.push(c),
+        }
+    }
+
+    let mut paths = vec![];
+    for elem in elems.into_iter().filter(|elem| !elem.is_empty()) {
+        match translate_windows_path(&elem, &opts.mount_root) {
+            Translation::Translated(path) if path.contains(':') => report
+                .warnings
+                .push(format!("{} in PATH is skipped since it has a colon.", elem)),
+            Translation::Translated(path) => {
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+            Translation::Unc => report.warnings.push(format!(
+                "{} in PATH is skipped since UNC paths are not supported.",
+                elem
+            )),
+            Translation::NotPath => report.warnings.push(format!(
+                "{} in PATH is skipped since it's not an absolute path.",
+                elem
+            )),
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envfile::EnvFileError;
+
+    fn gen_script(script: &EnvShellScript) -> String {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        script.write(tmp.path()).unwrap();
+        std::fs::read_to_string(tmp.path()).unwrap()
+    }
+
+    #[test]
+    fn test_translate_windows_path() {
+        assert_eq!(
+            Translation::Translated("/mnt/c/Users/me/bin".to_owned()),
+            translate_windows_path(r"C:\Users\me\bin\", "/mnt/")
+        );
+        assert_eq!(
+            Translation::Translated("/mnt/d".to_owned()),
+            translate_windows_path("D:", "/mnt")
+        );
+        assert_eq!(
+            Translation::Unc,
+            translate_windows_path(r"\\server\share", "/mnt")
+        );
+        assert_eq!(
+            Translation::NotPath,
+            translate_windows_path("Windows_NT", "/mnt")
+        );
+        assert_eq!(
+            Translation::NotPath,
+            translate_windows_path("C:foo", "/mnt")
+        );
+    }
+
+    #[test]
+    fn test_windows_path_to_wsl() {
+        let cases = [
+            (r"C:\Windows\System32", Some("/mnt/c/Windows/System32")),
+            (r"c:\windows", Some("/mnt/c/windows")),
+            (r"D:\", Some("/mnt/d")),
+            ("E:", Some("/mnt/e")),
+            (r"C:\Users\me\bin\", Some("/mnt/c/Users/me/bin")),
+            (
+                r"C:\Program Files\Git\cmd",
+                Some("/mnt/c/Program Files/Git/cmd"),
+            ),
+            (
+                r"C:\Program Files (x86)\Common Files",
+                Some("/mnt/c/Program Files (x86)/Common Files"),
+            ),
+            ("C:/Users/me//tools/", Some("/mnt/c/Users/me/tools")),
+            (r"\\?\C:\very\long\path", Some("/mnt/c/very/long/path")),
+            ("/mnt/c/Windows", Some("/mnt/c/Windows")),
+            ("/mnt//c/Users/me/", Some("/mnt/c/Users/me")),
+            (r"\\server\share\bin", None),
+            (r"\\?\UNC\server\share", None),
+            ("//server/share", None),
+            (r"C:\file:stream", None),
+            (r"Windows\System32", None),
+            ("C:relative", None),
+            ("/usr/bin", None),
+            ("%SystemRoot%", None),
+        ];
+        for (win_path, expected) in cases.iter() {
+            let translated = windows_path_to_wsl(win_path, "/mnt/");
+            assert_eq!(
+                *expected,
+                translated.as_ref().ok().map(String::as_str),
+                "{}",
+                win_path
+            );
+            if let Ok(translated) = translated {
+                assert_eq!(
+                    translated,
+                    windows_path_to_wsl(&translated, "/mnt").unwrap()
+                );
+            }
+        }
+
+        let err = windows_path_to_wsl(r"\\server\share", "/mnt").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WindowsPathError>(),
+            Some(WindowsPathError::Unsupported { .. })
+        ));
+        assert_eq!(
+            "/c/Windows",
+            windows_path_to_wsl(r"C:\Windows", "/").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_put_windows_path() {
+        let mut script = EnvShellScript::new();
+        script
+            .put_windows_path(r"C:\Program Files\Git\cmd\", false)
+            .unwrap();
+        assert!(script.put_windows_path(r"\\server\share", false).is_err());
+        assert!(gen_script(&script).contains("__CANDIDATE_PATHS='/mnt/c/Program Files/Git/cmd:'"));
+
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut tmp, b"PATH=/usr/bin:/bin\n").unwrap();
+        let mut env = EnvFile::open(tmp.path()).unwrap();
+        env.put_windows_path(r"C:\Windows\System32").unwrap();
+        let err = env.put_windows_path(r"C:\Users\O'Brien").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<EnvFileError>(),
+            Some(EnvFileError::InvalidValue { key, .. }) if key == "PATH"
+        ));
+        assert_eq!(
+            Some("'/mnt/c/Windows/System32':/usr/bin:/bin"),
+            env.get_env_raw("PATH")
+        );
+    }
+
+    #[test]
+    fn test_import_windows_env() {
+        let block = "=C:=C:\\\0\
+            Path=C:\\Windows\\system32;\"C:\\Program Files\\a;b\\bin\";\\\\server\\share\\bin;;%NOT_EXPANDED%;C:\\Users\\me\\bin\\\0\
+            USERPROFILE=C:\\Users\\me\0\
+            OS=Windows_NT\0\
+            ProgramFiles(x86)=C:\\Program Files (x86)\0\
+            HOMESHARE=\\\\server\\home\0\
+            SECRET=secret\0\0";
+        let opts = WindowsImportOptions {
+            denylist: vec!["secret".to_owned()],
+            ..WindowsImportOptions::default()
+        };
+        let mut script = EnvShellScript::new();
+        let report = script
+            .import_windows_env(parse_windows_env_block(block), &opts)
+            .unwrap();
+        assert_eq!(vec!["Path", "USERPROFILE", "OS"], report.imported);
+        assert_eq!(4, report.warnings.len(), "{:?}", report.warnings);
+        assert!(report.warnings[0].starts_with(r"\\server\share\bin in PATH"));
+        assert!(report.warnings[1].starts_with("%NOT_EXPANDED% in PATH"));
+        assert!(report.warnings[2].starts_with("ProgramFiles(x86)"));
+        assert!(report.warnings[3].starts_with("HOMESHARE"));
+
+        let cont = gen_script(&script);
+        assert!(cont.contains("export USERPROFILE='/mnt/c/Users/me'"));
+        assert!(cont.contains("export OS='Windows_NT'"));
+        assert!(cont.contains(
+            "__CANDIDATE_PATHS='/mnt/c/Windows/system32:/mnt/c/Program Files/a;b/bin:/mnt/c/Users/me/bin:'"
+        ));
+        assert!(cont.contains("export PATH=\"${PATH}:${__CANDIDATE_PATH}\""));
+        assert!(!cont.contains("SECRET"));
+    }
+
+    #[test]
+    fn test_import_windows_env_with_allowlist() {
+        let opts = WindowsImportOptions {
+            allowlist: Some(vec!["PATH".to_owned()]),
+            mount_root: "/".to_owned(),
+            prepends_paths: true,
+            ..WindowsImportOptions::default()
+        };
+        let vars = vec![
+            ("Path".to_owned(), r"C:\b;C:\a".to_owned()),
+            ("USERPROFILE".to_owned(), r"C:\Users\me".to_owned()),
+        ];
+        let mut script = EnvShellScript::new();
+        let report = script.import_windows_env(vars, &opts).unwrap();
+        assert_eq!(vec!["Path"], report.imported);
+        let cont = gen_script(&script);
+        // Prepended in the reverse order so that /c/b comes first.
+        assert!(cont.contains("__CANDIDATE_PATHS='/c/a:/c/b:'"));
+        assert!(!cont.contains("USERPROFILE"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::ops::BitOr;
+
+use anyhow::{Context, Result};
+
+use crate::envfile::{unquote_path_element, EnvFile};
+
+/// The flags of an entry of WSLENV, which tell WSL how to translate the variable.
+/// (See https://devblogs.microsoft.com/commandline/share-environment-vars-between-wsl-and-windows/)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WslEnvFlags(u8);
+
+impl WslEnvFlags {
+    pub const NONE: WslEnvFlags = WslEnvFlags(0);
+    /// Translate the value as a path.
+    pub const P: WslEnvFlags = WslEnvFlags(1);
+    /// Translate the value as a list of paths.
+    pub const L: WslEnvFlags = WslEnvFlags(1 << 1);
+    /// Share the variable only from Windows to WSL.
+    pub const U: WslEnvFlags = WslEnvFlags(1 << 2);
+    /// Share the variable only from WSL to Windows.
+    pub const W: WslEnvFlags = WslEnvFlags(1 << 3);
+
+    const LETTERS: [(WslEnvFlags, char); 4] = [
+        (WslEnvFlags::P, 'p'),
+        (WslEnvFlags::L, 'l'),
+        (WslEnvFlags::U, 'u'),
+        (WslEnvFlags::W, 'w'),
+    ];
+
+    pub fn contains(&self, other: WslEnvFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Parse the letters after `/`. Unknown letters are ignored.
+    fn parse(letters: &str) -> WslEnvFlags {
+        letters.chars().fold(WslEnvFlags::NONE, |flags, letter| {
+            WslEnvFlags::LETTERS
+                .iter()
+                .find(|(_, l)| *l == letter.to_ascii_lowercase())
+                .map_or(flags, |(flag, _)| flags | *flag)
+        })
+    }
+
+    fn to_letters(self) -> String {
+        WslEnvFlags::LETTERS
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, letter)| *letter)
+            .collect()
+    }
+}
+
+impl BitOr for WslEnvFlags {
+    type Output = WslEnvFlags;
+
+    fn bitor(self, rhs: WslEnvFlags) -> WslEnvFlags {
+        WslEnvFlags(self.0 | rhs.0)
+    }
+}
+
+/// WslEnv builds the value of WSLENV, a colon-separated list of `NAME/flags`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WslEnv {
+    entries: Vec<(String, WslEnvFlags)>,
+}
+
+impl WslEnv {
+    pub fn new() -> Self {
+        WslEnv::default()
+    }
+
+    /// Parse a value of WSLENV. Empty entries and entries without a name are skipped.
+    pub fn parse(value: &str) -> WslEnv {
+        let mut wsl_env = WslEnv::new();
+        for entry in value.split(':') {
+            let (name, letters) = entry.split_once('/').unwrap_or((entry, ""));
+            if name.is_empty() {
+                continue;
+            }
+            wsl_env.share(name, WslEnvFlags::parse(letters));
+        }
+        wsl_env
+    }
+
+    /// Share the variable `key`. The flags are updated if it's already shared.
+    pub fn share(&mut self, key: &str, flags: WslEnvFlags) {
+        match self.entries.iter_mut().find(|(name, _)| name == key) {
+            Some(entry) => entry.1 = flags,
+            None => self.entries.push((key.to_owned(), flags)),
+        }
+    }
+
+    pub fn flags(&self, key: &str) -> Option<WslEnvFlags> {
+        self.entries
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, flags)| *flags)
+    }
+
+    pub fn serialize(&self) -> String {
+        self.entries
+            .iter()
+            .map(|(name, flags)| {
+                if *flags == WslEnvFlags::NONE {
+                    name.clone()
+                } else {
+                    format!("{}/{}", name, flags.to_letters())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    /// Merge the entries into WSLENV of `env`. The existing entries keep their order, and
+    /// their flags are updated if they are shared with different ones.
+    pub fn merge_into_envfile(&self, env: &mut EnvFile) -> Result<()> {
+        let mut merged = match env.get_env_bytes("WSLENV") {
+            Some(value) => {
+                let value = std::str::from_utf8(value).with_context(|| {
+                    format!("WSLENV in {:?} is not valid UTF-8.", &env.file_path)
+                })?;
+                WslEnv::parse(&unquote_path_element(value))
+            }
+            None => WslEnv::new(),
+        };
+        for (name, flags) in &self.entries {
+            merged.share(name, *flags);
+        }
+        env.put_env("WSLENV".to_owned(), merged.serialize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse() {
+        let wsl_env = WslEnv::parse("GOPATH/l:USERPROFILE/pu:DISPLAY");
+        assert_eq!(Some(WslEnvFlags::L), wsl_env.flags("GOPATH"));
+        assert_eq!(
+            Some(WslEnvFlags::P | WslEnvFlags::U),
+            wsl_env.flags("USERPROFILE")
+        );
+        assert_eq!(Some(WslEnvFlags::NONE), wsl_env.flags("DISPLAY"));
+        assert_eq!("GOPATH/l:USERPROFILE/pu:DISPLAY", &wsl_env.serialize());
+
+        let wsl_env = WslEnv::parse("A/:B::/p:C/xU:");
+        assert_eq!("A:B:C/u", &wsl_env.serialize());
+
+        assert_eq!(WslEnv::new(), WslEnv::parse(""));
+        assert_eq!(WslEnv::new(), WslEnv::parse(":"));
+    }
+
+    #[test]
+    fn test_share() {
+        let mut wsl_env = WslEnv::new();
+        wsl_env.share("B", WslEnvFlags::P);
+        wsl_env.share("A", WslEnvFlags::W);
+        wsl_env.share("B", WslEnvFlags::L | WslEnvFlags::U);
+        assert_eq!("B/lu:A/w", &wsl_env.serialize());
+    }
+
+    #[test]
+    fn test_merge_into_envfile() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        writeln!(&mut tmp, "WSLENV=\"DISPLAY:GOPATH/p:\"").unwrap();
+        let mut env = EnvFile::open(tmp.path()).unwrap();
+
+        let mut wsl_env = WslEnv::new();
+        wsl_env.share("DISTROD_DISTRO", WslEnvFlags::U);
+        wsl_env.share("GOPATH", WslEnvFlags::L);
+        wsl_env.merge_into_envfile(&mut env).unwrap();
+        assert_eq!(
+            Some("DISPLAY:GOPATH/l:DISTROD_DISTRO/u"),
+            env.get_env("WSLENV").as_deref()
+        );
+
+        // It's idempotent.
+        wsl_env.merge_into_envfile(&mut env).unwrap();
+        assert_eq!(
+            Some("DISPLAY:GOPATH/l:DISTROD_DISTRO/u"),
+            env.get_env("WSLENV").as_deref()
+        );
+    }
+}